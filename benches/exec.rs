@@ -0,0 +1,87 @@
+use std::io;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use vm::vm::{assemble, Backend, VM};
+
+// counts to 100000, a jump and a few arithmetic ops per iteration
+const COUNT: &str = "
+mov cx 0
+loop:
+add cx 1
+mov ax cx
+mod ax 3
+cmp cx 100000
+gotoeq loop -1
+print cx
+";
+
+// 300 x 300 iterations, pushing and popping in the inner loop
+const NESTED: &str = "
+mov ax 0
+outer:
+mov bx 0
+inner:
+push bx
+pop cx
+add bx 1
+cmp bx 300
+gotoeq inner -1
+add ax 1
+cmp ax 300
+gotoeq outer -1
+print ax
+";
+
+// dispatches through a jump table 100000 times
+const DISPATCH: &str = "
+mov cx 0
+loop:
+mov ax cx
+mod ax 3
+mul ax 2
+add ax opi
+add ax 3
+jmp ax
+add bx 1
+goto next
+sub bx 1
+goto next
+mov bx 0
+next:
+add cx 1
+cmp cx 100000
+gotoeq loop -1
+print bx
+";
+
+fn bench(c: &mut Criterion, name: &str, src: &str) {
+    let code = assemble(src).unwrap();
+    let mut group = c.benchmark_group(name);
+    for (id, backend) in [
+        ("interpreter", Backend::Interpreter),
+        ("closures", Backend::Closures),
+    ] {
+        group.bench_function(id, |b| {
+            b.iter_batched(
+                || {
+                    let mut vm = VM::with_backend(backend);
+                    vm.set_output(Box::new(io::sink()));
+                    vm.load(code.clone());
+                    vm
+                },
+                |mut vm| vm.exec().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn exec(c: &mut Criterion) {
+    bench(c, "count", COUNT);
+    bench(c, "nested", NESTED);
+    bench(c, "dispatch", DISPATCH);
+}
+
+criterion_group!(benches, exec);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm::{
+    bytecode, verify,
+    vm::{assemble, ConstPool},
+};
+
+fuzz_target!(|src: &str| {
+    let Ok(code) = assemble(src) else {
+        return;
+    };
+    verify::verify(&code);
+    // `$N` operands may point past the pool, which is only an error once run
+    let consts = ConstPool::default();
+    let data = bytecode::encode(&code, &consts);
+    let (decoded, _) = bytecode::decode(&data).expect("encoded code decodes");
+    assert_eq!(bytecode::encode(&decoded, &consts), data);
+});
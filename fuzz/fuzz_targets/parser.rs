@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm::{codegen, lexer, parser, vm::ConstPool};
+
+fuzz_target!(|src: &str| {
+    let Ok(tokens) = parser::parse(src) else {
+        return;
+    };
+    match lexer::lex(tokens) {
+        Ok(program) => drop(codegen::gen(program, &mut ConstPool::default())),
+        Err(errors) => assert!(!errors.is_empty() && errors.len() <= parser::MAX_ERRORS),
+    }
+});
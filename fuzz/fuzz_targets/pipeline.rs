@@ -0,0 +1,23 @@
+#![no_main]
+
+use std::{io, iter};
+
+use libfuzzer_sys::fuzz_target;
+use vm::{codegen, eval::eval_program, lexer, parser, vm::VM};
+
+// source all the way through the VM, checked against the evaluator
+fuzz_target!(|src: &str| {
+    let Ok(tokens) = parser::parse(src) else {
+        return;
+    };
+    let Ok(program) = lexer::lex(tokens) else {
+        return;
+    };
+    let expected = eval_program(&program, &mut io::sink(), &mut iter::empty());
+    let mut vm = VM::with_output(Box::new(io::sink()));
+    vm.set_input(Box::new(iter::empty()));
+    let code = codegen::gen(program, vm.consts_mut());
+    vm.load(code);
+    let res = vm.exec().map(|()| vm.stack().last().cloned());
+    assert_eq!(format!("{res:?}"), format!("{expected:?}"));
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::{io, iter};
+
+use libfuzzer_sys::fuzz_target;
+use vm::{snapshot, vm::VM};
+
+const FUEL: u64 = 256;
+
+fn restore(data: &[u8]) {
+    let mut vm = VM::with_output(Box::new(io::sink()));
+    vm.set_input(Box::new(iter::empty()));
+    if vm.restore(data).is_err() {
+        return;
+    }
+    let _ = vm.exec_with_limit(FUEL);
+    VM::default()
+        .restore(&vm.snapshot())
+        .expect("a snapshot restores");
+}
+
+// random bytes hardly ever match the checksum, so the payload is also
+// sealed with a valid header to reach the decoder
+fuzz_target!(|data: &[u8]| {
+    restore(data);
+    restore(&snapshot::seal(data.to_vec()));
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm::parser;
+
+// the REPL draws a caret under token and error indexes, so they have to
+// point into the input
+fuzz_target!(|src: &str| {
+    match parser::parse(src) {
+        Ok(tokens) => {
+            for token in tokens {
+                assert!(token.index < src.len() && src.is_char_boundary(token.index));
+            }
+        }
+        Err(errors) => {
+            assert!(!errors.is_empty() && errors.len() <= parser::MAX_ERRORS);
+            for err in errors {
+                assert!(err.index <= src.len());
+            }
+        }
+    }
+});
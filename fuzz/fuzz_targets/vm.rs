@@ -0,0 +1,31 @@
+#![no_main]
+
+use std::{io, iter};
+
+use libfuzzer_sys::fuzz_target;
+use vm::{
+    bytecode, verify,
+    vm::{Backend, ConstPool, Op, VM},
+};
+
+// low enough that no program can build up more than a few hundred
+// megabytes of strings
+const FUEL: u64 = 256;
+
+fn run(code: Vec<Op>, consts: &ConstPool, backend: Backend) -> String {
+    let mut vm = VM::with_backend(backend);
+    vm.set_output(Box::new(io::sink()));
+    vm.set_input(Box::new(iter::repeat_with(|| Ok("1".to_string())).take(4)));
+    vm.load_linked(code, consts);
+    let res = vm.exec_with_limit(FUEL);
+    format!("{res:?} {:?} {:?} {}", vm.stack(), vm.regs(), vm.opptr())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((code, consts)) = bytecode::decode(data) else {
+        return;
+    };
+    verify::verify(&code);
+    let expected = run(code.clone(), &consts, Backend::Interpreter);
+    assert_eq!(run(code, &consts, Backend::Closures), expected);
+});
@@ -0,0 +1,411 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::vm::{ConstPool, Op, Operand, Reg, Value};
+
+const MAGIC: &[u8; 4] = b"VMB\0";
+const VERSION: u16 = 1;
+
+/// Serializes a program as
+///
+/// ```text
+/// magic "VMB\0", version: u16
+/// constant pool: count, then each string
+/// label table:   count, then each label name
+/// instructions:  count, then each opcode tag with its operands
+/// ```
+///
+/// Counts, indices and string lengths are LEB128 varints, labels are
+/// referenced by their index in the table.
+pub fn encode(code: &[Op], consts: &ConstPool) -> Vec<u8> {
+    let mut labels = HashMap::<&str, usize>::new();
+    let mut label_names = vec![];
+    for id in code.iter().filter_map(Op::label) {
+        labels.entry(id).or_insert_with(|| {
+            label_names.push(id);
+            label_names.len() - 1
+        });
+    }
+    let mut e = Encoder::default();
+    e.raw(MAGIC);
+    e.u16(VERSION);
+    e.usize(consts.len());
+    consts.iter().for_each(|x| e.str(x));
+    e.usize(label_names.len());
+    label_names.iter().for_each(|x| e.str(x));
+    e.usize(code.len());
+    for op in code {
+        let label = op.label().map(|id| labels[id]).unwrap_or_default();
+        match op {
+            Op::Push(val) => {
+                e.u8(0);
+                e.operand(val);
+            }
+            Op::Pop(reg) => {
+                e.u8(1);
+                e.reg(*reg);
+            }
+            Op::Add(reg, val) => e.reg_operand(2, *reg, val),
+            Op::Sub(reg, val) => e.reg_operand(3, *reg, val),
+            Op::Mul(reg, val) => e.reg_operand(4, *reg, val),
+            Op::Div(reg, val) => e.reg_operand(5, *reg, val),
+            Op::Mod(reg, val) => e.reg_operand(6, *reg, val),
+            Op::Pow(reg, val) => e.reg_operand(7, *reg, val),
+            Op::IDiv(reg, val) => e.reg_operand(8, *reg, val),
+            Op::Mov(reg, val) => e.reg_operand(9, *reg, val),
+            Op::Cmp(val1, val2) => {
+                e.u8(10);
+                e.operand(val1);
+                e.operand(val2);
+            }
+            Op::Len(reg) => {
+                e.u8(11);
+                e.reg(*reg);
+            }
+            Op::Print(val) => {
+                e.u8(12);
+                e.operand(val);
+            }
+            Op::Read(reg) => {
+                e.u8(13);
+                e.reg(*reg);
+            }
+            Op::Mark(_) => {
+                e.u8(14);
+                e.usize(label);
+            }
+            Op::Goto(_) => {
+                e.u8(15);
+                e.usize(label);
+            }
+            Op::GotoEq(_, val) => {
+                e.u8(16);
+                e.usize(label);
+                e.operand(val);
+            }
+            Op::Jmp(val) => {
+                e.u8(17);
+                e.operand(val);
+            }
+        }
+    }
+    e.into_inner()
+}
+
+/// Inverse of `encode`. Label references are checked, constants outside of
+/// the pool are kept as they are in loaded code and fail with
+/// `NoSuchConstant` once run.
+pub fn decode(data: &[u8]) -> Result<(Vec<Op>, ConstPool), DecodeError> {
+    let mut d = Decoder::new(data);
+    if d.raw(MAGIC.len()) != Ok(&MAGIC[..]) {
+        Err(DecodeError::BadMagic)?
+    }
+    let version = d.u16()?;
+    if version != VERSION {
+        Err(DecodeError::UnsupportedVersion(version))?
+    }
+    let mut consts = ConstPool::default();
+    for (index, string) in d.list(Decoder::str)?.into_iter().enumerate() {
+        if consts.intern(string) != index {
+            Err(DecodeError::DuplicateConstant)?
+        }
+    }
+    let labels = d.list(Decoder::str)?;
+    let label = |d: &mut Decoder| {
+        let index = d.usize()?;
+        labels
+            .get(index)
+            .map(|x| x.to_string())
+            .ok_or(DecodeError::InvalidLabel(index))
+    };
+    let code = d.list(|d| {
+        let op = match d.u8()? {
+            0 => Op::Push(d.operand()?),
+            1 => Op::Pop(d.reg()?),
+            2 => Op::Add(d.reg()?, d.operand()?),
+            3 => Op::Sub(d.reg()?, d.operand()?),
+            4 => Op::Mul(d.reg()?, d.operand()?),
+            5 => Op::Div(d.reg()?, d.operand()?),
+            6 => Op::Mod(d.reg()?, d.operand()?),
+            7 => Op::Pow(d.reg()?, d.operand()?),
+            8 => Op::IDiv(d.reg()?, d.operand()?),
+            9 => Op::Mov(d.reg()?, d.operand()?),
+            10 => Op::Cmp(d.operand()?, d.operand()?),
+            11 => Op::Len(d.reg()?),
+            12 => Op::Print(d.operand()?),
+            13 => Op::Read(d.reg()?),
+            14 => Op::Mark(label(d)?),
+            15 => Op::Goto(label(d)?),
+            16 => Op::GotoEq(label(d)?, d.operand()?),
+            17 => Op::Jmp(d.operand()?),
+            tag => Err(DecodeError::InvalidTag(tag))?,
+        };
+        Ok(op)
+    })?;
+    d.finish()?;
+    Ok((code, consts))
+}
+
+/// Little-endian writer for the primitives shared by bytecode files and
+/// snapshots.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn raw(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    /// LEB128 varint.
+    pub fn usize(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    pub fn option_usize(&mut self, value: Option<usize>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.usize(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub fn option_u64(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u64(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.buf.extend(value);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::Int(x) => {
+                self.u8(0);
+                self.u64(*x as u64);
+            }
+            Value::Float(x) => {
+                self.u8(1);
+                self.u64(x.to_bits());
+            }
+            Value::Bool(x) => {
+                self.u8(2);
+                self.u8(*x as u8);
+            }
+            Value::Str(x) => {
+                self.u8(3);
+                self.str(x);
+            }
+        }
+    }
+
+    pub fn reg(&mut self, reg: Reg) {
+        self.u8(match reg {
+            Reg::AX => 0,
+            Reg::BX => 1,
+            Reg::CX => 2,
+            Reg::Cmp => 3,
+            Reg::OpPtr => 4,
+        })
+    }
+
+    pub fn operand(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Lit(x) => {
+                self.u8(0);
+                self.value(x);
+            }
+            Operand::Reg(x) => {
+                self.u8(1);
+                self.reg(*x);
+            }
+            Operand::Const(x) => {
+                self.u8(2);
+                self.usize(*x);
+            }
+        }
+    }
+
+    fn reg_operand(&mut self, tag: u8, reg: Reg, val: &Operand) {
+        self.u8(tag);
+        self.reg(reg);
+        self.operand(val);
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Fails if anything is left unread.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.pos == self.buf.len() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingData)
+        }
+    }
+
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|x| *x <= self.buf.len())
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let res = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.raw(2)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, DecodeError> {
+        let mut res = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.u8()?;
+            let bits = ((byte & 0x7f) as usize)
+                .checked_shl(shift)
+                .filter(|x| x >> shift == (byte & 0x7f) as usize)
+                .ok_or(DecodeError::InvalidVarint)?;
+            res |= bits;
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    pub fn option_usize(&mut self) -> Result<Option<usize>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.usize()?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn option_u64(&mut self) -> Result<Option<u64>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.u64()?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.usize()?;
+        self.raw(len)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, DecodeError> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    /// Reads a count followed by that many items.
+    pub fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>, DecodeError>
+    where
+        F: FnMut(&mut Self) -> Result<T, DecodeError>,
+    {
+        // the count is untrusted, so nothing is reserved up front
+        let mut res = vec![];
+        for _ in 0..self.usize()? {
+            res.push(item(self)?);
+        }
+        Ok(res)
+    }
+
+    pub fn value(&mut self) -> Result<Value, DecodeError> {
+        match self.u8()? {
+            0 => Ok(Value::Int(self.u64()? as i64)),
+            1 => Ok(Value::Float(f64::from_bits(self.u64()?))),
+            2 => match self.u8()? {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                tag => Err(DecodeError::InvalidTag(tag)),
+            },
+            3 => Ok(Value::Str(Rc::from(self.str()?))),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn reg(&mut self) -> Result<Reg, DecodeError> {
+        match self.u8()? {
+            0 => Ok(Reg::AX),
+            1 => Ok(Reg::BX),
+            2 => Ok(Reg::CX),
+            3 => Ok(Reg::Cmp),
+            4 => Ok(Reg::OpPtr),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn operand(&mut self) -> Result<Operand, DecodeError> {
+        match self.u8()? {
+            0 => Ok(Operand::Lit(self.value()?)),
+            1 => Ok(Operand::Reg(self.reg()?)),
+            2 => Ok(Operand::Const(self.usize()?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    TrailingData,
+    InvalidTag(u8),
+    InvalidVarint,
+    InvalidUtf8,
+    InvalidLabel(usize),
+    DuplicateConstant,
+}
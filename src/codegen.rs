@@ -1,28 +1,72 @@
 use crate::{
-    lexer::Expr,
-    parser::OpType,
-    vm::{Op, Reg, Value},
+    lexer::{Expr, Program},
+    parser::{Builtin, OpType},
+    vm::{ConstPool, Op, Operand, Reg, Value},
 };
 
-pub fn gen(ast: Box<Expr>) -> Vec<Op> {
+/// Compiles the whole program into one piece of code, which leaves the
+/// value of the last statement on the stack and nothing else.
+pub fn gen(program: Program, consts: &mut ConstPool) -> Vec<Op> {
+    gen_seq(&program.statements, consts)
+}
+
+// the values of all but the last expression are dropped
+fn gen_seq(exprs: &[Expr], consts: &mut ConstPool) -> Vec<Op> {
+    let mut res = vec![];
+    for (index, expr) in exprs.iter().enumerate() {
+        if index > 0 {
+            res.push(Op::Pop(Reg::AX));
+        }
+        res.extend(gen_expr(Box::new(expr.clone()), consts));
+    }
+    res
+}
+
+fn gen_expr(ast: Box<Expr>, consts: &mut ConstPool) -> Vec<Op> {
     match ast.as_ref() {
-        Expr::Number(num) => vec![Op::Push(Value::Lit(*num))],
+        Expr::Int(num) => vec![Op::Push(Operand::Lit(Value::Int(*num)))],
+        Expr::Float(num) => vec![Op::Push(Operand::Lit(Value::Float(*num)))],
+        Expr::Str(string) => vec![Op::Push(Operand::Const(consts.intern(string)))],
         Expr::Binary(op, lhs, rhs) => {
             let mut res = vec![];
-            res.extend(gen(lhs.clone()));
-            res.extend(gen(rhs.clone()));
+            res.extend(gen_expr(lhs.clone(), consts));
+            res.extend(gen_expr(rhs.clone(), consts));
             res.extend([
                 Op::Pop(Reg::BX),
                 Op::Pop(Reg::AX),
                 match op {
-                    OpType::Add => Op::Add(Reg::AX, Value::Reg(Reg::BX)),
-                    OpType::Sub => Op::Sub(Reg::AX, Value::Reg(Reg::BX)),
-                    OpType::Mul => Op::Mul(Reg::AX, Value::Reg(Reg::BX)),
-                    OpType::Div => Op::Div(Reg::AX, Value::Reg(Reg::BX)),
+                    OpType::Add => Op::Add(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::Sub => Op::Sub(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::Mul => Op::Mul(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::Div => Op::Div(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::Mod => Op::Mod(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::Pow => Op::Pow(Reg::AX, Operand::Reg(Reg::BX)),
+                    OpType::IDiv => Op::IDiv(Reg::AX, Operand::Reg(Reg::BX)),
                 },
-                Op::Push(Value::Reg(Reg::AX)),
+                Op::Push(Operand::Reg(Reg::AX)),
             ]);
             res
         }
+        Expr::Call(builtin, args) => {
+            let mut res = vec![];
+            for arg in args {
+                res.extend(gen_expr(Box::new(arg.clone()), consts));
+            }
+            match builtin {
+                Builtin::Len => res.extend([
+                    Op::Pop(Reg::AX),
+                    Op::Len(Reg::AX),
+                    Op::Push(Operand::Reg(Reg::AX)),
+                ]),
+                Builtin::Read => res.extend([Op::Read(Reg::AX), Op::Push(Operand::Reg(Reg::AX))]),
+                Builtin::Print => res.extend([
+                    Op::Pop(Reg::AX),
+                    Op::Print(Operand::Reg(Reg::AX)),
+                    Op::Push(Operand::Reg(Reg::AX)),
+                ]),
+            }
+            res
+        }
+        Expr::Block(exprs) => gen_seq(exprs, consts),
     }
 }
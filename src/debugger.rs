@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+
+use crate::vm::{Op, Reg, StepResult, VM};
+
+const HELP: &str = "\
+break [INDEX|LABEL]  set a breakpoint, or list them without an argument
+delete INDEX|LABEL   remove a breakpoint
+step                 execute one instruction
+next                 run until the instruction after the current one
+continue             run until a breakpoint is hit or the program halts
+print REG            show a register
+stack                show the stack, top last
+disasm               show the program
+quit                 leave the debugger";
+
+pub struct Debugger<'a> {
+    vm: &'a mut VM,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut VM) -> Self {
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        self.show_current();
+        loop {
+            let mut buf = String::new();
+            print!("(dbg) ");
+            io::stdout().flush().unwrap();
+            if io::stdin().read_line(&mut buf).unwrap() == 0 {
+                return;
+            }
+            let args = buf.split_whitespace().collect::<Vec<_>>();
+            match args.as_slice() {
+                [] => {}
+                ["break" | "b"] => {
+                    for index in &self.breakpoints {
+                        println!("{index:>4}  {}", self.vm.code()[*index]);
+                    }
+                }
+                ["break" | "b", target] => match self.resolve_target(target) {
+                    Some(index) => drop(self.breakpoints.insert(index)),
+                    None => println!("no instruction or label `{target}`"),
+                },
+                ["delete" | "d", target] => match self.resolve_target(target) {
+                    Some(index) => drop(self.breakpoints.remove(&index)),
+                    None => println!("no instruction or label `{target}`"),
+                },
+                ["step" | "s"] => {
+                    let res = self.vm.step();
+                    if self.report(res) {
+                        self.show_current();
+                    }
+                }
+                ["next" | "n"] => {
+                    let next = self.vm.opptr() + 1;
+                    self.run_until(|vm| vm.opptr() == next);
+                }
+                ["continue" | "c"] => self.run_until(|_| false),
+                ["print" | "p", reg] => match Reg::try_from(*reg) {
+                    Ok(reg) => println!("{}", self.vm.regs().get(reg)),
+                    Err(()) => println!("no register `{reg}`"),
+                },
+                ["stack"] => {
+                    for (index, value) in self.vm.stack().iter().enumerate() {
+                        println!("{index:>4}  {value}");
+                    }
+                }
+                ["disasm"] => self.disasm(),
+                ["help" | "h"] => println!("{HELP}"),
+                ["quit" | "q"] => return,
+                _ => println!("unknown command, try `help`"),
+            }
+        }
+    }
+
+    fn resolve_target(&self, target: &str) -> Option<usize> {
+        let code = self.vm.code();
+        if let Ok(index) = target.parse::<usize>() {
+            return (index < code.len()).then_some(index);
+        }
+        // jumps land right after the mark, so that is where to stop
+        code.iter()
+            .position(|op| matches!(op, Op::Mark(name) if name == target))
+            .map(|index| (index + 1).min(code.len() - 1))
+    }
+
+    // steps at least once, so that continuing from a breakpoint moves on
+    fn run_until<F: Fn(&VM) -> bool>(&mut self, stop: F) {
+        loop {
+            let res = self.vm.step();
+            if !self.report(res) {
+                return;
+            }
+            if stop(&*self.vm) || self.breakpoints.contains(&self.vm.opptr()) {
+                break;
+            }
+        }
+        if self.breakpoints.contains(&self.vm.opptr()) {
+            println!("breakpoint at {}", self.vm.opptr());
+        }
+        self.show_current();
+    }
+
+    // tells whether execution can go on
+    fn report(&self, res: StepResult) -> bool {
+        match res {
+            StepResult::Continued => true,
+            StepResult::Halted => {
+                println!("program halted");
+                false
+            }
+            StepResult::Error(err) => {
+                println!("{:?} at {}", err, self.vm.opptr());
+                false
+            }
+        }
+    }
+
+    fn show_current(&self) {
+        match self.vm.current_op() {
+            Some(op) => println!("{:>4}  {}", self.vm.opptr(), op),
+            None => println!("program halted"),
+        }
+    }
+
+    fn disasm(&self) {
+        for (index, op) in self.vm.code().iter().enumerate() {
+            let current = if index == self.vm.opptr() { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&index) {
+                '*'
+            } else {
+                ' '
+            };
+            println!("{current}{breakpoint}{index:>4}  {op}");
+        }
+    }
+}
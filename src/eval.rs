@@ -0,0 +1,166 @@
+use std::io::{self, Write};
+
+use crate::{
+    lexer::{Expr, Program},
+    parser::{Builtin, OpType},
+    vm::{ExecutionError, Value, MAX_STR_LEN},
+};
+
+/// Evaluates the statements in order, to the value of the last one or
+/// `None` for an empty program.
+pub fn eval_program(
+    program: &Program,
+    output: &mut dyn Write,
+    input: &mut dyn Iterator<Item = io::Result<String>>,
+) -> Result<Option<Value>, ExecutionError> {
+    let mut res = None;
+    for expr in &program.statements {
+        res = Some(eval(expr, output, input)?);
+    }
+    Ok(res)
+}
+
+/// Evaluates an expression by walking the tree, the reference `codegen`
+/// and the VM are checked against. Builtins print to `output` and read lines
+/// from `input` like the VM does.
+///
+/// The arithmetic is spelled out here rather than calling the `Value`
+/// methods the VM runs, so that a mistake in either one shows up as a
+/// difference.
+pub fn eval(
+    expr: &Expr,
+    output: &mut dyn Write,
+    input: &mut dyn Iterator<Item = io::Result<String>>,
+) -> Result<Value, ExecutionError> {
+    match expr {
+        Expr::Int(num) => Ok(Value::Int(*num)),
+        Expr::Float(num) => Ok(Value::Float(*num)),
+        Expr::Str(string) => Ok(Value::Str(string.as_str().into())),
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(lhs, output, input)?;
+            let rhs = eval(rhs, output, input)?;
+            binary(*op, &lhs, &rhs)
+        }
+        Expr::Call(builtin, args) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, output, input))
+                .collect::<Result<Vec<_>, _>>()?;
+            match (builtin, args.as_slice()) {
+                (Builtin::Len, [Value::Str(string)]) => {
+                    Ok(Value::Int(string.chars().count() as i64))
+                }
+                (Builtin::Len, [_]) => Err(ExecutionError::TypeError),
+                (Builtin::Print, [val]) => {
+                    writeln!(output, "{val}")
+                        .and_then(|_| output.flush())
+                        .map_err(|err| ExecutionError::OutputError(err.kind()))?;
+                    Ok(val.clone())
+                }
+                (Builtin::Read, []) => {
+                    let line = input
+                        .next()
+                        .ok_or(ExecutionError::EndOfInput)?
+                        .map_err(|err| ExecutionError::InputError(err.kind()))?;
+                    let line = line.trim();
+                    match (line.parse::<i64>(), line.parse::<f64>()) {
+                        (Ok(num), _) => Ok(Value::Int(num)),
+                        (_, Ok(num)) => Ok(Value::Float(num)),
+                        _ => Err(ExecutionError::InvalidInput),
+                    }
+                }
+                _ => unreachable!("the lexer checks the number of arguments"),
+            }
+        }
+        Expr::Block(exprs) => {
+            let mut res = None;
+            for expr in exprs {
+                res = Some(eval(expr, output, input)?);
+            }
+            Ok(res.expect("the lexer rejects empty blocks"))
+        }
+    }
+}
+
+// both sides of an operator once their types are settled: two ints stay
+// ints, anything else numeric is promoted to floats
+enum Operands {
+    Ints(i64, i64),
+    Floats(f64, f64),
+}
+
+fn float(val: &Value) -> Result<f64, ExecutionError> {
+    match val {
+        Value::Int(num) => Ok(*num as f64),
+        Value::Float(num) => Ok(*num),
+        Value::Bool(_) | Value::Str(_) => Err(ExecutionError::TypeError),
+    }
+}
+
+fn binary(op: OpType, lhs: &Value, rhs: &Value) -> Result<Value, ExecutionError> {
+    if let (OpType::Add, Value::Str(_), _) | (OpType::Add, _, Value::Str(_)) = (op, lhs, rhs) {
+        let mut res = lhs.to_string();
+        res.push_str(&rhs.to_string());
+        if res.len() > MAX_STR_LEN {
+            Err(ExecutionError::StringTooLong)?
+        }
+        return Ok(Value::Str(res.into()));
+    }
+    let operands = match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => Operands::Ints(*a, *b),
+        _ => Operands::Floats(float(lhs)?, float(rhs)?),
+    };
+    let (lhs_zero, rhs_zero) = match operands {
+        Operands::Ints(a, b) => (a == 0, b == 0),
+        Operands::Floats(a, b) => (a == 0., b == 0.),
+    };
+    let negative_exponent = match operands {
+        Operands::Ints(_, b) => b < 0,
+        Operands::Floats(_, b) => b < 0.,
+    };
+    match op {
+        OpType::Div | OpType::Mod | OpType::IDiv if rhs_zero => {
+            Err(ExecutionError::ZeroDivisionError)?
+        }
+        OpType::Pow if lhs_zero && negative_exponent => Err(ExecutionError::ZeroDivisionError)?,
+        _ => {}
+    }
+    let int = |res: Option<i64>| res.map(Value::Int).ok_or(ExecutionError::IntegerOverflow);
+    match (op, operands) {
+        (OpType::Add, Operands::Ints(a, b)) => int(a.checked_add(b)),
+        (OpType::Sub, Operands::Ints(a, b)) => int(a.checked_sub(b)),
+        (OpType::Mul, Operands::Ints(a, b)) => int(a.checked_mul(b)),
+        // true division always gives a float
+        (OpType::Div, Operands::Ints(a, b)) => Ok(Value::Float(a as f64 / b as f64)),
+        // floored, the result takes the sign of the divisor
+        (OpType::Mod, Operands::Ints(a, b)) => int(a.checked_rem(b).map(|r| {
+            if r != 0 && (r < 0) != (b < 0) {
+                r + b
+            } else {
+                r
+            }
+        })),
+        // floored, rounding towards negative infinity
+        (OpType::IDiv, Operands::Ints(a, b)) => int(a.checked_div(b).map(|q| {
+            if q * b != a && (a < 0) != (b < 0) {
+                q - 1
+            } else {
+                q
+            }
+        })),
+        (OpType::Pow, Operands::Ints(a, b)) if b >= 0 => match a {
+            // these never overflow, however large the exponent
+            0 | 1 => Ok(Value::Int(if b == 0 { 1 } else { a })),
+            -1 => Ok(Value::Int(if b % 2 == 0 { 1 } else { -1 })),
+            _ => int(u32::try_from(b).ok().and_then(|b| a.checked_pow(b))),
+        },
+        (OpType::Pow, Operands::Ints(a, b)) => Ok(Value::Float((a as f64).powf(b as f64))),
+        (OpType::Add, Operands::Floats(a, b)) => Ok(Value::Float(a + b)),
+        (OpType::Sub, Operands::Floats(a, b)) => Ok(Value::Float(a - b)),
+        (OpType::Mul, Operands::Floats(a, b)) => Ok(Value::Float(a * b)),
+        (OpType::Div, Operands::Floats(a, b)) => Ok(Value::Float(a / b)),
+        (OpType::Mod, Operands::Floats(a, b)) => Ok(Value::Float(a - b * (a / b).floor())),
+        (OpType::IDiv, Operands::Floats(a, b)) => Ok(Value::Float((a / b).floor())),
+        (OpType::Pow, Operands::Floats(a, b)) => Ok(Value::Float(a.powf(b))),
+    }
+}
@@ -1,83 +1,304 @@
 use std::mem;
 
-use crate::parser::{OpType, Token, TokenValue};
+use crate::parser::{Builtin, OpType, Token, TokenValue, MAX_ERRORS};
 
 #[derive(Clone, Debug)]
 pub enum Expr {
-    Number(f64),
+    Int(i64),
+    Float(f64),
+    Str(String),
     Binary(OpType, Box<Expr>, Box<Expr>),
+    Call(Builtin, Vec<Expr>),
+    Block(Vec<Expr>),
 }
 
+/// What a whole input is made of, statements run one after another. Like
+/// a block, its value is that of the last one.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub statements: Vec<Expr>,
+}
+
+// an expression keeps the token it starts at for errors about it, and is
+// `None` when it has errors of its own already reported
 enum IntermediateExpr {
-    Owned(Expr),
+    Owned(Option<Expr>, Token),
     Token(Token),
     Empty,
 }
 
+/// Builds the program the tokens spell out, statements separated by `;`
+/// or line breaks. Blank statements are skipped, so blank input is an empty
+/// program. Every token has to be part of a statement's expression, anything
+/// left over is an error.
+///
+/// An error gives up on the statement or parentheses it is in and carries
+/// on after them, so one pass reports every error, up to
+/// `parser::MAX_ERRORS`.
 pub fn lex<I: IntoIterator<Item = Token>>(tokens: I) -> LexerResult {
     let mut tokens = tokens
         .into_iter()
         .map(|x| {
-            if let TokenValue::Num(num) = x.value {
-                IntermediateExpr::Owned(Expr::Number(num))
-            } else {
-                IntermediateExpr::Token(x)
-            }
+            let expr = match &x.value {
+                TokenValue::Int(num) => Expr::Int(*num),
+                TokenValue::Float(num) => Expr::Float(*num),
+                TokenValue::Str(string) => Expr::Str(string.clone()),
+                TokenValue::Builtin(builtin) if builtin.arity() == 0 => {
+                    Expr::Call(*builtin, vec![])
+                }
+                _ => return IntermediateExpr::Token(x),
+            };
+            IntermediateExpr::Owned(Some(expr), x)
         })
         .collect::<Vec<_>>();
-    process(&mut tokens)?;
-    Ok(tokens
-        .into_iter()
-        .find_map(|x| {
-            if let IntermediateExpr::Owned(expr) = x {
-                Some(Box::new(expr))
-            } else {
-                None
+    let mut errors = vec![];
+    match statements(&mut tokens, &mut errors) {
+        Some(statements) => Ok(Program { statements }),
+        None => {
+            // groups are done before what is around them, so errors come
+            // out of order
+            errors.sort_by_key(|x| x.token.index);
+            errors.truncate(MAX_ERRORS);
+            Err(errors)
+        }
+    }
+}
+
+// separators inside parentheses or braces are not the ones splitting these
+// statements, so they are left to the group. A line break right after an
+// operator does not end the statement either, its operands are on the next
+// line.
+fn statements(tokens: &mut [IntermediateExpr], errors: &mut Vec<LexError>) -> Option<Vec<Expr>> {
+    let mut res = Some(vec![]);
+    let mut depth = 0usize;
+    let mut after_operator = false;
+    let mut start = 0;
+    for end in 0..=tokens.len() {
+        if let Some(x) = tokens.get(end) {
+            let IntermediateExpr::Token(token) = x else {
+                after_operator = false;
+                continue;
+            };
+            let ends_statement = match token.value {
+                TokenValue::LP | TokenValue::LBrace => {
+                    depth += 1;
+                    false
+                }
+                TokenValue::RP | TokenValue::RBrace => {
+                    depth = depth.saturating_sub(1);
+                    false
+                }
+                TokenValue::Semicolon => depth == 0,
+                TokenValue::Newline => depth == 0 && !after_operator,
+                _ => false,
+            };
+            if !matches!(token.value, TokenValue::Newline) {
+                after_operator =
+                    matches!(token.value, TokenValue::Op(..) | TokenValue::Builtin(..));
+            }
+            if !ends_statement {
+                continue;
             }
+        }
+        let statement = &mut tokens[start..end];
+        start = end + 1;
+        if !process(statement, errors) {
+            res = None;
+            continue;
+        }
+        let expr =
+            statement
+                .iter_mut()
+                .find_map(|x| match mem::replace(x, IntermediateExpr::Empty) {
+                    IntermediateExpr::Owned(expr, _) => expr,
+                    _ => None,
+                });
+        if let (Some(res), Some(expr)) = (&mut res, expr) {
+            res.push(expr);
+        }
+    }
+    res
+}
+
+/// Whether more input could complete the tokens, which is when parentheses
+/// or braces are left open or the last token is an operator still waiting
+/// for its operands. A closing one too many can never be fixed that way.
+pub fn is_incomplete(tokens: &[Token]) -> bool {
+    let mut depth = 0usize;
+    for token in tokens {
+        match token.value {
+            TokenValue::LP | TokenValue::LBrace => depth += 1,
+            TokenValue::RP | TokenValue::RBrace if depth == 0 => return false,
+            TokenValue::RP | TokenValue::RBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    let last = tokens
+        .iter()
+        .rev()
+        .find(|x| !matches!(x.value, TokenValue::Newline));
+    depth > 0
+        || last.is_some_and(|x| match x.value {
+            TokenValue::Op(..) => true,
+            TokenValue::Builtin(builtin) => builtin.arity() > 0,
+            _ => false,
         })
-        .unwrap())
 }
 
-fn process(tokens: &mut [IntermediateExpr]) -> Result<(), LexError> {
-    while let Some((lpar_index, rpar_index)) = find_parenthesises(tokens)? {
-        tokens[lpar_index] = IntermediateExpr::Empty;
-        tokens[rpar_index] = IntermediateExpr::Empty;
-        process(&mut tokens[lpar_index + 1..rpar_index])?;
+// reports whether the tokens made an expression without errors
+fn process(tokens: &mut [IntermediateExpr], errors: &mut Vec<LexError>) -> bool {
+    let reported = errors.len();
+    if let Err(err) = process_level(tokens, errors) {
+        errors.push(err)
     }
-    while let Some((op_index, arg_indexes)) = find_expr(tokens)? {
-        if let IntermediateExpr::Token(
+    errors.len() == reported
+}
+
+fn process_level(
+    tokens: &mut [IntermediateExpr],
+    errors: &mut Vec<LexError>,
+) -> Result<(), LexError> {
+    while let Some((open_index, close_index)) = find_group(tokens)? {
+        let IntermediateExpr::Token(open) =
+            mem::replace(&mut tokens[open_index], IntermediateExpr::Empty)
+        else {
+            unreachable!()
+        };
+        tokens[close_index] = IntermediateExpr::Empty;
+        let group = &mut tokens[open_index + 1..close_index];
+        if let TokenValue::LBrace = open.value {
+            let expr = match statements(group, errors) {
+                Some(exprs) if exprs.is_empty() => {
+                    errors.push(LexError {
+                        token: open.clone(),
+                        value: LexErrorValue::EmptyBlock,
+                    });
+                    None
+                }
+                exprs => exprs.map(Expr::Block),
+            };
+            group.fill_with(|| IntermediateExpr::Empty);
+            tokens[open_index] = IntermediateExpr::Owned(expr, open);
+        } else if !process(group, errors) {
+            // the group stands in for a single argument so that it does
+            // not make the expression around it wrong too
+            group.fill_with(|| IntermediateExpr::Empty);
+            tokens[open_index] = IntermediateExpr::Owned(None, open);
+        } else if !group
+            .iter()
+            .any(|x| matches!(x, IntermediateExpr::Owned(..)))
+        {
+            errors.push(LexError {
+                token: open.clone(),
+                value: LexErrorValue::EmptyParenthesis,
+            });
+            tokens[open_index] = IntermediateExpr::Owned(None, open);
+        }
+    }
+    // what is left of separators belongs to parentheses, where only line
+    // breaks are allowed
+    for x in tokens.iter_mut() {
+        match x {
+            IntermediateExpr::Token(Token {
+                value: TokenValue::Newline,
+                ..
+            }) => *x = IntermediateExpr::Empty,
+            IntermediateExpr::Token(
+                token @ Token {
+                    value: TokenValue::Semicolon,
+                    ..
+                },
+            ) => Err(LexError {
+                token: token.clone(),
+                value: LexErrorValue::UnexpectedToken,
+            })?,
+            _ => {}
+        }
+    }
+    if let Some(token) = tokens.iter().find_map(|x| match x {
+        IntermediateExpr::Token(
             token @ Token {
-                value: TokenValue::Op(op),
+                value: TokenValue::RP | TokenValue::RBrace,
                 ..
             },
-        ) = tokens[op_index]
+        ) => Some(token),
+        _ => None,
+    }) {
+        Err(unmatched(token))?
+    }
+    while let Some((op_index, arg_indexes)) = find_expr(tokens)? {
+        let token = if let IntermediateExpr::Token(token) =
+            mem::replace(&mut tokens[op_index], IntermediateExpr::Empty)
         {
-            if arg_indexes.len() != 2 {
-                Err(LexError {
-                    token,
-                    value: LexErrorValue::InvalidNumberOfArguments,
-                })?
-            }
-            let lhs = mem::replace(&mut tokens[arg_indexes[0]], IntermediateExpr::Empty);
-            let rhs = mem::replace(&mut tokens[arg_indexes[1]], IntermediateExpr::Empty);
-            if let (IntermediateExpr::Owned(lhs), IntermediateExpr::Owned(rhs)) = (lhs, rhs) {
-                tokens[op_index] =
-                    IntermediateExpr::Owned(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
-            } else {
-                unreachable!()
-            }
+            token
         } else {
             unreachable!()
+        };
+        let arity = match token.value {
+            TokenValue::Op(..) => 2,
+            TokenValue::Builtin(builtin) => builtin.arity(),
+            _ => unreachable!(),
+        };
+        if let Some(&index) = arg_indexes.get(arity) {
+            let IntermediateExpr::Owned(_, token) = &tokens[index] else {
+                unreachable!()
+            };
+            Err(LexError {
+                token: token.clone(),
+                value: LexErrorValue::UnexpectedToken,
+            })?
         }
+        if arg_indexes.len() != arity {
+            Err(LexError {
+                token: token.clone(),
+                value: LexErrorValue::InvalidNumberOfArguments,
+            })?
+        }
+        let args = arg_indexes
+            .into_iter()
+            .map(|index| {
+                if let IntermediateExpr::Owned(arg, _) =
+                    mem::replace(&mut tokens[index], IntermediateExpr::Empty)
+                {
+                    arg
+                } else {
+                    unreachable!()
+                }
+            })
+            .collect::<Option<Vec<_>>>();
+        let expr = args.map(|args| match token.value {
+            TokenValue::Op(op) => {
+                let [lhs, rhs] = <[Expr; 2]>::try_from(args)
+                    .unwrap_or_else(|_| unreachable!("operators take two arguments"));
+                Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+            }
+            TokenValue::Builtin(builtin) => Expr::Call(builtin, args),
+            _ => unreachable!(),
+        });
+        tokens[op_index] = IntermediateExpr::Owned(expr, token);
+    }
+    if let Some(token) = tokens
+        .iter()
+        .filter_map(|x| match x {
+            IntermediateExpr::Owned(_, token) => Some(token),
+            _ => None,
+        })
+        .nth(1)
+    {
+        Err(LexError {
+            token: token.clone(),
+            value: LexErrorValue::UnexpectedToken,
+        })?
     }
     Ok(())
 }
 
-fn find_parenthesises(tokens: &[IntermediateExpr]) -> Result<Option<(usize, usize)>, LexError> {
-    let lpar = tokens.iter().enumerate().find_map(|(i, x)| {
+// the first parentheses or braces and where they are closed
+fn find_group(tokens: &[IntermediateExpr]) -> Result<Option<(usize, usize)>, LexError> {
+    let open = tokens.iter().enumerate().find_map(|(i, x)| {
         if let IntermediateExpr::Token(
             token @ Token {
-                value: TokenValue::LP,
+                value: TokenValue::LP | TokenValue::LBrace,
                 ..
             },
         ) = x
@@ -87,35 +308,42 @@ fn find_parenthesises(tokens: &[IntermediateExpr]) -> Result<Option<(usize, usiz
             None
         }
     });
-    if let Some((lpar_index, lpar_token)) = lpar {
-        let mut par_count = 1;
-        let offset = tokens.iter().skip(lpar_index + 1).position(|x| {
-            par_count += matches!(
-                x,
-                IntermediateExpr::Token(Token {
-                    value: TokenValue::LP,
-                    ..
-                })
-            ) as i32;
-            par_count -= matches!(
-                x,
-                IntermediateExpr::Token(Token {
-                    value: TokenValue::RP,
-                    ..
-                })
-            ) as i32;
-            par_count == 0
-        });
-        if let Some(offset) = offset {
-            Ok(Some((lpar_index, offset + lpar_index + 1)))
-        } else {
-            Err(LexError {
-                token: *lpar_token,
-                value: LexErrorValue::UnmatchedParenthesis,
-            })
+    let Some((open_index, open_token)) = open else {
+        return Ok(None);
+    };
+    let mut depth = 0;
+    for (index, x) in tokens.iter().enumerate().skip(open_index) {
+        let IntermediateExpr::Token(token) = x else {
+            continue;
+        };
+        match token.value {
+            TokenValue::LP | TokenValue::LBrace => depth += 1,
+            TokenValue::RP | TokenValue::RBrace => depth -= 1,
+            _ => continue,
         }
-    } else {
-        Ok(None)
+        if depth == 0 {
+            let matching = matches!(
+                (&open_token.value, &token.value),
+                (TokenValue::LP, TokenValue::RP) | (TokenValue::LBrace, TokenValue::RBrace)
+            );
+            return if matching {
+                Ok(Some((open_index, index)))
+            } else {
+                Err(unmatched(token))
+            };
+        }
+    }
+    Err(unmatched(open_token))
+}
+
+fn unmatched(token: &Token) -> LexError {
+    let value = match token.value {
+        TokenValue::LBrace | TokenValue::RBrace => LexErrorValue::UnmatchedBrace,
+        _ => LexErrorValue::UnmatchedParenthesis,
+    };
+    LexError {
+        token: token.clone(),
+        value,
     }
 }
 
@@ -124,7 +352,7 @@ fn find_expr(tokens: &[IntermediateExpr]) -> Result<Option<(usize, Vec<usize>)>,
         matches!(
             x,
             IntermediateExpr::Token(Token {
-                value: TokenValue::Op(..),
+                value: TokenValue::Op(..) | TokenValue::Builtin(..),
                 ..
             })
         )
@@ -148,9 +376,9 @@ fn find_expr(tokens: &[IntermediateExpr]) -> Result<Option<(usize, Vec<usize>)>,
     }
 }
 
-pub type LexerResult = Result<Box<Expr>, LexError>;
+pub type LexerResult = Result<Program, Vec<LexError>>;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct LexError {
     pub token: Token,
     pub value: LexErrorValue,
@@ -159,5 +387,9 @@ pub struct LexError {
 #[derive(Clone, Copy, Debug)]
 pub enum LexErrorValue {
     UnmatchedParenthesis,
+    UnmatchedBrace,
+    EmptyParenthesis,
+    EmptyBlock,
     InvalidNumberOfArguments,
+    UnexpectedToken,
 }
@@ -0,0 +1,11 @@
+pub mod bytecode;
+pub mod codegen;
+pub mod debugger;
+pub mod eval;
+pub mod lexer;
+pub mod parser;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
+pub mod verify;
+pub mod vm;
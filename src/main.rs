@@ -1,38 +1,314 @@
-use std::{io::{self, Write}, iter};
+use std::{env, fmt, fs, io, path::PathBuf, process};
 
-use crate::vm::VM;
-
-mod codegen;
-mod lexer;
-mod parser;
-mod vm;
+use ::vm::{
+    bytecode, codegen,
+    debugger::Debugger,
+    lexer,
+    parser::{self, Builtin},
+    trace::TextTracer,
+    verify::{self, Severity},
+    vm::{self, ConstPool, Op, Reg, VM},
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
 
 fn main() {
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("compile") => return compile(args.skip(1)),
+        Some("run") => drop(args.next()),
+        _ => {}
+    }
+    let mut debug = false;
+    let mut trace = false;
+    let mut profile = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "--trace" => trace = true,
+            "--profile" => profile = true,
+            _ if arg.starts_with('-') || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        if debug || profile {
+            usage()
+        }
+        return repl(trace);
+    };
+    let (code, consts) = if path.ends_with(".vmb") {
+        bytecode::decode(&read(&path)).unwrap_or_else(|err| {
+            eprintln!("{path}: {err:?}");
+            process::exit(1)
+        })
+    } else {
+        (assemble(&path), ConstPool::default())
+    };
+    let diagnostics = verify::verify(&code);
+    for diagnostic in &diagnostics {
+        eprintln!("{path}: {diagnostic}");
+    }
+    // the debugger is there to look into broken programs, so let it run them
+    if !debug
+        && diagnostics
+            .iter()
+            .any(|x| x.kind.severity() == Severity::Error)
+    {
+        process::exit(1)
+    }
+    let mut vm = new_vm(trace);
+    vm.load_linked(code, &consts);
+    if profile {
+        vm.enable_profiling();
+    }
+    let res = if debug {
+        Debugger::new(&mut vm).run();
+        Ok(())
+    } else {
+        vm.exec()
+    };
+    if let Some(profile) = vm.profile() {
+        eprint!("{}", profile.report(vm.code()));
+    }
+    if let Err(err) = res {
+        eprintln!("{:?} at {}", err, vm.opptr());
+        process::exit(1)
+    }
+}
+
+fn compile<I: Iterator<Item = String>>(mut args: I) {
+    let mut out = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let out = out.unwrap_or_else(|| format!("{}.vmb", path.trim_end_matches(".asm")));
+    let mut code = assemble(&path);
+    let mut consts = ConstPool::default();
+    consts.intern_literals(&mut code);
+    if let Err(err) = fs::write(&out, bytecode::encode(&code, &consts)) {
+        eprintln!("{out}: {err}");
+        process::exit(1)
+    }
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("{path}: {err}");
+        process::exit(1)
+    })
+}
+
+fn read_to_string(path: &str) -> String {
+    String::from_utf8(read(path)).unwrap_or_else(|err| {
+        eprintln!("{path}: {err}");
+        process::exit(1)
+    })
+}
+
+fn assemble(path: &str) -> Vec<Op> {
+    vm::assemble(&read_to_string(path)).unwrap_or_else(|err| {
+        eprintln!("{path}:{}: {:?}", err.line, err.value);
+        process::exit(1)
+    })
+}
+
+fn usage() -> ! {
+    eprintln!("usage: vm [run] [--debug] [--trace] [--profile] [FILE.asm | FILE.vmb]");
+    eprintln!("       vm compile [-o OUT.vmb] FILE.asm");
+    process::exit(2)
+}
+
+fn new_vm(trace: bool) -> VM {
     let mut vm = VM::default();
+    if trace {
+        vm.set_tracer(Some(Box::new(TextTracer::new(Box::new(io::stderr())))));
+    }
+    vm
+}
+
+const HELP: &str = "\
+:stack  show the stack, top last
+:regs   show the registers
+:help   show this
+:quit   leave, like Ctrl-D";
+
+const COMMANDS: [&str; 4] = [":stack", ":regs", ":help", ":quit"];
+
+// completes builtins, or commands at the start of the line
+struct Completions;
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+            .len();
+        let word = &line[start..pos];
+        let names = if word.starts_with(':') {
+            if start > 0 {
+                return Ok((start, vec![]));
+            }
+            COMMANDS.to_vec()
+        } else {
+            Builtin::ALL.map(Builtin::name).to_vec()
+        };
+        let candidates = names
+            .into_iter()
+            .filter(|x| x.starts_with(word))
+            .map(String::from)
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".vm_history"))
+}
+
+fn repl(trace: bool) {
+    let mut editor = Editor::<Completions, DefaultHistory>::new().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1)
+    });
+    editor.set_helper(Some(Completions));
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is none yet on the first run
+        let _ = editor.load_history(path);
+    }
+    let mut vm = new_vm(trace);
+    let mut src = String::new();
     loop {
-        let mut buf = String::new();
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let _expr = io::stdin().read_line(&mut buf).unwrap();
-        let tokens = match parser::parse(buf.trim()) {
-            Ok(tokens) => tokens,
+        let line = match editor.readline(prompt(src.is_empty())) {
+            Ok(line) => line,
+            // Ctrl-C drops what was entered so far, Ctrl-D leaves
+            Err(ReadlineError::Interrupted) => {
+                src.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
             Err(err) => {
-                let offset = iter::repeat(' ').take(err.index + 2).collect::<String>();
-                println!("{}↑ {:?}", offset, err.value);
+                eprintln!("{err}");
+                break;
+            }
+        };
+        if src.is_empty() && line.trim_start().starts_with(':') {
+            let _ = editor.add_history_entry(line.trim());
+            match line.trim() {
+                ":stack" => {
+                    for (index, value) in vm.stack().iter().enumerate() {
+                        println!("{index:>4}  {value}");
+                    }
+                }
+                ":regs" => {
+                    for reg in [Reg::AX, Reg::BX, Reg::CX, Reg::Cmp, Reg::OpPtr] {
+                        println!("{:<4} {}", reg.to_string(), vm.regs().get(reg));
+                    }
+                }
+                ":help" => println!("{HELP}"),
+                ":quit" => break,
+                _ => println!("unknown command, try `:help`"),
+            }
+            continue;
+        }
+        src.push_str(&line);
+        src.push('\n');
+        let tokens = match parser::parse(&src) {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                for err in errors {
+                    report(&src, err.index, err.value);
+                }
+                finish(&mut editor, &mut src);
                 continue;
             }
         };
-        let ast = match lexer::lex(tokens) {
-            Ok(ast) => ast,
-            Err(err) => {
-                let offset = iter::repeat(' ').take(err.token.index + 2).collect::<String>();
-                println!("{}↑ {:?}", offset, err.value);
+        // keep reading lines until the statement is complete
+        if lexer::is_incomplete(&tokens) {
+            continue;
+        }
+        let program = match lexer::lex(tokens) {
+            Ok(program) => program,
+            Err(errors) => {
+                for err in errors {
+                    report(&src, err.token.index, err.value);
+                }
+                finish(&mut editor, &mut src);
                 continue;
             }
         };
-        let asm = codegen::gen(ast);
+        finish(&mut editor, &mut src);
+        if program.statements.is_empty() {
+            continue;
+        }
+        let asm = codegen::gen(program, vm.consts_mut());
         vm.load(asm);
-        vm.exec().unwrap();
-        println!("{}", vm.stack().back().unwrap());
+        if let Err(err) = vm.exec() {
+            println!("{:?}", err);
+            vm = new_vm(trace);
+            continue;
+        }
+        println!("{}", vm.stack().last().unwrap());
+    }
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("{}: {err}", path.display());
+        }
+    }
+}
+
+// a statement spread over several lines goes into the history as one entry,
+// so that recalling it brings back all of it
+fn finish(editor: &mut Editor<Completions, DefaultHistory>, src: &mut String) {
+    if !src.trim().is_empty() {
+        let _ = editor.add_history_entry(src.trim());
+    }
+    src.clear();
+}
+
+fn prompt(first_line: bool) -> &'static str {
+    if first_line {
+        "> "
+    } else {
+        ".. "
+    }
+}
+
+// points at the error under the line just entered, earlier lines of the
+// input are shown again first
+fn report(src: &str, index: usize, err: impl fmt::Debug) {
+    let start = src[..index].rfind('\n').map_or(0, |x| x + 1);
+    let end = src[index..].find('\n').map_or(src.len(), |x| index + x);
+    let prompt = prompt(start == 0);
+    if end + 1 < src.len() {
+        println!("{}{}", prompt, &src[start..end]);
     }
+    let offset = " ".repeat(prompt.len() + index - start);
+    println!("{}↑ {:?}", offset, err);
 }
@@ -1,15 +1,24 @@
-#[derive(Clone, Copy, Debug)]
+use std::mem;
+
+#[derive(Clone, Debug)]
 pub struct Token {
     pub index: usize,
     pub value: TokenValue,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum TokenValue {
     LP,
     RP,
+    LBrace,
+    RBrace,
+    Semicolon,
+    Newline,
     Op(OpType),
-    Num(f64),
+    Builtin(Builtin),
+    Int(i64),
+    Float(f64),
+    Str(String),
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -18,6 +27,9 @@ pub enum OpType {
     Sub,
     Mul,
     Div,
+    Mod,
+    Pow,
+    IDiv,
 }
 
 impl OpType {
@@ -27,135 +39,359 @@ impl OpType {
             '-' => Some(OpType::Sub),
             '*' => Some(OpType::Mul),
             '/' => Some(OpType::Div),
+            '%' => Some(OpType::Mod),
+            '^' => Some(OpType::Pow),
+            _ => None,
+        }
+    }
+
+    // `**` and `//` are spelled with two chars, so the second one is merged
+    // into the operator token pushed right before it
+    fn try_merge(self, value: char) -> Option<Self> {
+        match (self, value) {
+            (OpType::Mul, '*') => Some(OpType::Pow),
+            (OpType::Div, '/') => Some(OpType::IDiv),
             _ => None,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum Builtin {
+    Len,
+    Print,
+    Read,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 3] = [Builtin::Len, Builtin::Print, Builtin::Read];
+
+    fn try_from(value: &str) -> Option<Self> {
+        Builtin::ALL.into_iter().find(|x| x.name() == value)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Len => "len",
+            Builtin::Print => "print",
+            Builtin::Read => "read",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Read => 0,
+            Builtin::Len | Builtin::Print => 1,
+        }
+    }
+}
+
+fn push_op(tokens: &mut Vec<Token>, index: usize, op: OpType, c: char) {
+    if let Some(Token {
+        index: prev_index,
+        value: TokenValue::Op(prev),
+    }) = tokens.last_mut()
+    {
+        if *prev_index + 1 == index {
+            if let Some(merged) = prev.try_merge(c) {
+                *prev = merged;
+                return;
+            }
+        }
+    }
+    tokens.push(Token {
+        index,
+        value: TokenValue::Op(op),
+    })
+}
+
 enum State {
     Empty,
-    LeadingDot,
-    Number(String),
+    Number(usize, String),
+    Ident(usize, String),
+    // start, contents so far and index of a pending backslash
+    Str(usize, String, Option<usize>),
+    // after an error, until somewhere to pick up again
+    Skip,
+    // a string met while skipping, whether a backslash is pending
+    SkipStr(bool),
 }
 
+/// Diagnostics reported for one input at most, past that they are mostly
+/// fallout from the first ones.
+pub const MAX_ERRORS: usize = 10;
+
+/// Splits a program into tokens, separated by any whitespace. Line breaks
+/// end statements like `;` does, so they are tokens too.
+///
+/// An error skips to the next line break, `;`, `)` or `}` outside of a
+/// string and carries on from there, so one pass reports every error, up
+/// to `MAX_ERRORS`.
 pub fn parse(expr: &str) -> ParseResult {
     let mut state = State::Empty;
     let mut tokens = vec![];
+    let mut errors = vec![];
     for (index, c) in expr.char_indices() {
-        match state {
-            State::Empty => {
-                if let Some(op) = OpType::try_from(c) {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Op(op),
-                    })
-                } else if c.is_ascii_digit() {
-                    state = State::Number(c.to_string())
-                } else if c == '.' {
-                    state = State::LeadingDot;
-                } else if c == '(' {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::LP,
-                    })
-                } else if c == ')' {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::RP,
-                    })
-                } else if c != ' ' {
-                    Err(ParseError {
-                        index,
-                        value: ParseErrorValue::UnexpectedCharacter,
-                    })?
-                }
+        if errors.len() == MAX_ERRORS {
+            break;
+        }
+        if let Err(err) = step(&mut state, &mut tokens, index, c) {
+            errors.push(err);
+            // a bad escape leaves the string open, it still ends at its quote
+            if !matches!(state, State::Str(..)) {
+                // the token that failed may have been ended by `c`, which
+                // can still be the place to pick up again
+                state = State::Skip;
+                step(&mut state, &mut tokens, index, c).expect("skipping never fails");
+            }
+        }
+    }
+    let last = match state {
+        State::Empty | State::Skip | State::SkipStr(_) => Ok(()),
+        State::Number(start, num) => parse_number(start, &num).map(|value| {
+            tokens.push(Token {
+                index: start,
+                value,
+            })
+        }),
+        State::Ident(start, ident) => parse_ident(start, &ident).map(|value| {
+            tokens.push(Token {
+                index: start,
+                value,
+            })
+        }),
+        State::Str(start, ..) => Err(ParseError {
+            index: start,
+            value: ParseErrorValue::UnterminatedString,
+        }),
+    };
+    if let Err(err) = last {
+        errors.push(err);
+    }
+    errors.truncate(MAX_ERRORS);
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+fn step(
+    state: &mut State,
+    tokens: &mut Vec<Token>,
+    index: usize,
+    c: char,
+) -> Result<(), ParseError> {
+    match state {
+        State::Empty => {}
+        State::Skip => {
+            // what is quoted is not where to pick up
+            if c == '"' {
+                *state = State::SkipStr(false);
+                return Ok(());
+            }
+            if !matches!(c, '\n' | ';' | ')' | '}') {
+                return Ok(());
+            }
+        }
+        State::SkipStr(escape) => {
+            if *escape {
+                *escape = false;
+            } else if c == '\\' {
+                *escape = true;
+            } else if c == '"' {
+                *state = State::Skip;
+            }
+            return Ok(());
+        }
+        State::Number(start, num) => {
+            if continues_number(num, c) {
+                num.push(c);
+                return Ok(());
+            }
+            tokens.push(Token {
+                index: *start,
+                value: parse_number(*start, num)?,
+            });
+        }
+        State::Ident(start, ident) => {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                ident.push(c);
+                return Ok(());
+            }
+            tokens.push(Token {
+                index: *start,
+                value: parse_ident(*start, ident)?,
+            });
+        }
+        State::Str(start, string, escape) => {
+            if let Some(escape_index) = escape.take() {
+                string.push(unescape(escape_index, c)?);
+            } else if c == '\\' {
+                *escape = Some(index);
+            } else if c == '"' {
+                tokens.push(Token {
+                    index: *start,
+                    value: TokenValue::Str(mem::take(string)),
+                });
+                *state = State::Empty;
+            } else {
+                string.push(c);
             }
-            State::Number(ref mut num) => {
-                if let Some(op) = OpType::try_from(c) {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Num(num.parse::<f64>().unwrap()),
-                    });
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Op(op),
-                    });
-                    state = State::Empty;
-                } else if c.is_ascii_digit() {
-                    num.push(c);
-                } else if c == '.' {
-                    if num.contains('.') {
-                        Err(ParseError {
-                            index,
-                            value: ParseErrorValue::MultipleDots,
-                        })?
-                    }
-                    num.push(c)
-                } else if c == '(' {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Num(num.parse::<f64>().unwrap()),
-                    });
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::LP,
-                    });
-                    state = State::Empty;
-                } else if c == ')' {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Num(num.parse::<f64>().unwrap()),
-                    });
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::RP,
-                    });
-                    state = State::Empty;
-                } else if c == ' ' {
-                    tokens.push(Token {
-                        index,
-                        value: TokenValue::Num(num.parse::<f64>().unwrap()),
-                    });
-                    state = State::Empty
-                } else {
-                    Err(ParseError {
-                        index,
-                        value: ParseErrorValue::UnexpectedCharacter,
-                    })?
+            return Ok(());
+        }
+    }
+    *state = State::Empty;
+    if let Some(op) = OpType::try_from(c) {
+        push_op(tokens, index, op, c)
+    } else if c.is_ascii_digit() || c == '.' {
+        *state = State::Number(index, c.to_string())
+    } else if c.is_ascii_alphabetic() || c == '_' {
+        *state = State::Ident(index, c.to_string())
+    } else if c == '"' {
+        *state = State::Str(index, String::new(), None)
+    } else if let Some(value) = punctuation(c) {
+        tokens.push(Token { index, value })
+    } else if !c.is_whitespace() {
+        Err(ParseError {
+            index,
+            value: ParseErrorValue::UnexpectedCharacter,
+        })?
+    }
+    Ok(())
+}
+
+fn punctuation(c: char) -> Option<TokenValue> {
+    match c {
+        '(' => Some(TokenValue::LP),
+        ')' => Some(TokenValue::RP),
+        '{' => Some(TokenValue::LBrace),
+        '}' => Some(TokenValue::RBrace),
+        ';' => Some(TokenValue::Semicolon),
+        '\n' => Some(TokenValue::Newline),
+        _ => None,
+    }
+}
+
+fn parse_ident(index: usize, ident: &str) -> Result<TokenValue, ParseError> {
+    Builtin::try_from(ident)
+        .map(TokenValue::Builtin)
+        .ok_or(ParseError {
+            index,
+            value: ParseErrorValue::UnknownIdentifier,
+        })
+}
+
+fn unescape(index: usize, c: char) -> Result<char, ParseError> {
+    match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        '\\' => Ok('\\'),
+        '"' => Ok('"'),
+        _ => Err(ParseError {
+            index,
+            value: ParseErrorValue::InvalidEscape,
+        }),
+    }
+}
+
+fn radix_prefix(num: &str) -> Option<(u32, ParseErrorValue)> {
+    match num.get(..2) {
+        Some("0x" | "0X") => Some((16, ParseErrorValue::InvalidHexDigit)),
+        Some("0b" | "0B") => Some((2, ParseErrorValue::InvalidBinaryDigit)),
+        _ => None,
+    }
+}
+
+// everything that may appear inside a literal is collected first and
+// validated as a whole by `parse_number`, so errors point at the exact char
+fn continues_number(num: &str, c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || c == '_'
+        || c == '.'
+        || matches!(c, '+' | '-') && num.ends_with(['e', 'E']) && radix_prefix(num).is_none()
+}
+
+fn parse_number(index: usize, num: &str) -> Result<TokenValue, ParseError> {
+    let error = |offset: usize, value| ParseError {
+        index: index + offset,
+        value,
+    };
+    let bytes = num.as_bytes();
+    let is_digit_at = |offset: usize, radix: u32| {
+        bytes
+            .get(offset)
+            .is_some_and(|x| (*x as char).is_digit(radix))
+    };
+    if let Some((radix, invalid_digit)) = radix_prefix(num) {
+        let mut res = 0i64;
+        for (offset, c) in num.char_indices().skip(2) {
+            if c == '_' {
+                if offset == 2 || !is_digit_at(offset - 1, radix) || !is_digit_at(offset + 1, radix)
+                {
+                    Err(error(offset, ParseErrorValue::MisplacedUnderscore))?
                 }
+            } else if let Some(digit) = c.to_digit(radix) {
+                res = res
+                    .checked_mul(radix as i64)
+                    .and_then(|x| x.checked_add(digit as i64))
+                    .ok_or(error(0, ParseErrorValue::NumberTooLarge))?;
+            } else {
+                Err(error(offset, invalid_digit))?
             }
-            State::LeadingDot => {
-                if c.is_ascii_digit() {
-                    state = State::Number(c.to_string());
-                } else if c == '.' {
-                    Err(ParseError {
-                        index,
-                        value: ParseErrorValue::MultipleDots,
-                    })?
-                } else {
-                    Err(ParseError {
-                        index,
-                        value: ParseErrorValue::SingleDot,
-                    })?
+        }
+        if num.len() == 2 {
+            Err(error(2, invalid_digit))?
+        }
+        return Ok(TokenValue::Int(res));
+    }
+    let mut dot = None;
+    let mut exp = None;
+    for (offset, c) in num.char_indices() {
+        match c {
+            '0'..='9' => {}
+            '_' => {
+                if offset == 0 || !is_digit_at(offset - 1, 10) || !is_digit_at(offset + 1, 10) {
+                    Err(error(offset, ParseErrorValue::MisplacedUnderscore))?
                 }
             }
+            '.' if exp.is_some() => Err(error(offset, ParseErrorValue::InvalidExponent))?,
+            '.' if dot.is_some() => Err(error(offset, ParseErrorValue::MultipleDots))?,
+            '.' => dot = Some(offset),
+            'e' | 'E' if exp.is_some() => Err(error(offset, ParseErrorValue::InvalidExponent))?,
+            'e' | 'E' => exp = Some(offset),
+            '+' | '-' => {}
+            _ => Err(error(offset, ParseErrorValue::UnexpectedCharacter))?,
         }
     }
-    match state {
-        State::Empty => {}
-        State::LeadingDot => Err(ParseError {
-            index: expr.len() - 1,
-            value: ParseErrorValue::SingleDot,
-        })?,
-        State::Number(num) => tokens.push(Token {
-            index: expr.len() - num.len(),
-            value: TokenValue::Num(num.parse::<f64>().unwrap()),
-        }),
+    let mantissa_end = exp.unwrap_or(num.len());
+    if !num[..mantissa_end].bytes().any(|x| x.is_ascii_digit()) {
+        Err(error(dot.unwrap_or(0), ParseErrorValue::SingleDot))?
+    }
+    if let Some(exp) = exp {
+        let digits = num[exp + 1..].trim_start_matches(['+', '-']);
+        if digits.is_empty() || !digits.bytes().all(|x| x.is_ascii_digit()) {
+            Err(error(exp, ParseErrorValue::InvalidExponent))?
+        }
+    }
+    let num = num.replace('_', "");
+    if dot.is_none() && exp.is_none() {
+        num.parse::<i64>()
+            .map(TokenValue::Int)
+            .map_err(|_| error(0, ParseErrorValue::NumberTooLarge))
+    } else {
+        match num.parse::<f64>() {
+            // too large for a float rounds to infinity instead of failing
+            Ok(num) if num.is_infinite() => Err(error(0, ParseErrorValue::NumberTooLarge)),
+            Ok(num) => Ok(TokenValue::Float(num)),
+            Err(_) => Err(error(0, ParseErrorValue::UnexpectedCharacter)),
+        }
     }
-    Ok(tokens)
 }
 
-pub type ParseResult = Result<Vec<Token>, ParseError>;
+pub type ParseResult = Result<Vec<Token>, Vec<ParseError>>;
 
 #[derive(Clone, Copy, Debug)]
 pub struct ParseError {
@@ -168,4 +404,12 @@ pub enum ParseErrorValue {
     UnexpectedCharacter,
     SingleDot,
     MultipleDots,
+    InvalidExponent,
+    InvalidHexDigit,
+    InvalidBinaryDigit,
+    MisplacedUnderscore,
+    NumberTooLarge,
+    UnknownIdentifier,
+    UnterminatedString,
+    InvalidEscape,
 }
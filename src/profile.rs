@@ -0,0 +1,89 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use crate::vm::Op;
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Sample {
+    count: u64,
+    time: Duration,
+}
+
+impl Sample {
+    fn add(&mut self, other: Sample) {
+        self.count += other.count;
+        self.time += other.time;
+    }
+}
+
+/// Execution counts and time collected per instruction index while
+/// profiling is enabled on the VM.
+#[derive(Default, Debug)]
+pub struct Profile {
+    samples: Vec<Sample>,
+}
+
+impl Profile {
+    pub fn record(&mut self, index: usize, time: Duration) {
+        if self.samples.len() <= index {
+            self.samples.resize(index + 1, Sample::default());
+        }
+        self.samples[index].add(Sample { count: 1, time });
+    }
+
+    pub fn total(&self) -> u64 {
+        self.samples.iter().map(|x| x.count).sum()
+    }
+
+    /// Renders counts and time per instruction, per opcode and per enclosing
+    /// label, each sorted from the most executed with the time summed up
+    /// along the way.
+    pub fn report(&self, code: &[Op]) -> String {
+        let mut by_op = HashMap::<&str, Sample>::new();
+        let mut by_label = HashMap::<&str, Sample>::new();
+        let mut by_index = vec![];
+        let mut label = "<start>";
+        for (index, op) in code.iter().enumerate() {
+            if let Op::Mark(name) = op {
+                label = name;
+            }
+            let sample = self.samples.get(index).copied().unwrap_or_default();
+            if sample.count == 0 {
+                continue;
+            }
+            by_op.entry(op.mnemonic()).or_default().add(sample);
+            by_label.entry(label).or_default().add(sample);
+            by_index.push((format!("{index:>4}  {op}"), sample));
+        }
+        let mut res = String::new();
+        self.section(&mut res, "instruction", by_index);
+        self.section(&mut res, "opcode", by_op.into_iter().collect());
+        self.section(&mut res, "label", by_label.into_iter().collect());
+        res
+    }
+
+    fn section<K: AsRef<str>>(&self, res: &mut String, title: &str, mut rows: Vec<(K, Sample)>) {
+        let total = self.total().max(1) as f64;
+        rows.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(b.1.time.cmp(&a.1.time)));
+        writeln!(
+            res,
+            "{:>10} {:>7} {:>12} {:>12}  {title}",
+            "count", "%", "time", "cumulative"
+        )
+        .unwrap();
+        let mut cumulative = Duration::ZERO;
+        for (key, sample) in rows {
+            cumulative += sample.time;
+            writeln!(
+                res,
+                "{:>10} {:>6.2}% {:>12?} {:>12?}  {}",
+                sample.count,
+                sample.count as f64 * 100. / total,
+                sample.time,
+                cumulative,
+                key.as_ref(),
+            )
+            .unwrap();
+        }
+        res.push('\n');
+    }
+}
@@ -0,0 +1,56 @@
+use crate::bytecode::DecodeError;
+
+const MAGIC: &[u8; 4] = b"VMSS";
+const VERSION: u16 = 3;
+// magic, version, payload length and checksum
+const HEADER_LEN: usize = 4 + 2 + 8 + 8;
+
+/// Prepends the versioned header to a snapshot payload.
+pub fn seal(payload: Vec<u8>) -> Vec<u8> {
+    let mut res = Vec::with_capacity(HEADER_LEN + payload.len());
+    res.extend(MAGIC);
+    res.extend(VERSION.to_le_bytes());
+    res.extend((payload.len() as u64).to_le_bytes());
+    res.extend(checksum(&payload).to_le_bytes());
+    res.extend(payload);
+    res
+}
+
+/// Checks the header written by `seal` and returns the payload.
+pub fn open(data: &[u8]) -> Result<&[u8], SnapshotError> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        Err(SnapshotError::BadMagic)?
+    }
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != VERSION {
+        Err(SnapshotError::UnsupportedVersion(version))?
+    }
+    let len = u64::from_le_bytes(data[6..14].try_into().unwrap());
+    let sum = u64::from_le_bytes(data[14..22].try_into().unwrap());
+    let payload = &data[HEADER_LEN..];
+    if payload.len() as u64 != len || checksum(payload) != sum {
+        Err(SnapshotError::ChecksumMismatch)?
+    }
+    Ok(payload)
+}
+
+// FNV-1a, enough to notice a truncated or damaged file
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, x| {
+        (hash ^ *x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(value: DecodeError) -> Self {
+        SnapshotError::Decode(value)
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::Write;
+
+use crate::vm::{Op, Reg, Tracer, VM};
+
+/// Logs every executed instruction along with the registers and the stack
+/// top it left behind.
+pub struct TextTracer {
+    out: Box<dyn Write>,
+    opptr: usize,
+}
+
+impl TextTracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { out, opptr: 0 }
+    }
+}
+
+impl Tracer for TextTracer {
+    fn before_op(&mut self, vm: &VM, _op: &Op) {
+        self.opptr = vm.opptr();
+    }
+
+    fn after_op(&mut self, vm: &VM, op: &Op) {
+        let regs = vm.regs();
+        let reg = |reg| regs.get(reg);
+        let top = vm
+            .stack()
+            .last()
+            .map_or("-".to_string(), ToString::to_string);
+        // tracing must not break the traced program, so write errors are dropped
+        let _ = writeln!(
+            self.out,
+            "{:>4}  {:<24} ax={} bx={} cx={} cmp={} top={}",
+            self.opptr,
+            op.to_string(),
+            reg(Reg::AX),
+            reg(Reg::BX),
+            reg(Reg::CX),
+            reg(Reg::Cmp),
+            top,
+        );
+    }
+}
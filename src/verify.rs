@@ -0,0 +1,189 @@
+use std::{collections::HashMap, fmt};
+
+use crate::vm::{Op, Reg};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub enum DiagnosticKind {
+    NoSuchMark(String),
+    DuplicateMark(String),
+    StackUnderflow,
+    InconsistentStackDepth(usize, usize),
+    IllegalRegister(Reg),
+    Unreachable,
+}
+
+impl DiagnosticKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            DiagnosticKind::Unreachable => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub index: usize,
+    pub kind: DiagnosticKind,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.kind.severity() {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity} at {}: ", self.index)?;
+        match &self.kind {
+            DiagnosticKind::NoSuchMark(id) => write!(f, "no mark `{id}`"),
+            DiagnosticKind::DuplicateMark(id) => write!(f, "mark `{id}` is defined twice"),
+            DiagnosticKind::StackUnderflow => write!(f, "pop may run on an empty stack"),
+            DiagnosticKind::InconsistentStackDepth(a, b) => {
+                write!(f, "reached with stack depth {a} and {b}")
+            }
+            DiagnosticKind::IllegalRegister(reg) => write!(f, "illegal use of register {reg}"),
+            DiagnosticKind::Unreachable => write!(f, "unreachable code"),
+        }
+    }
+}
+
+/// Statically checks a program that starts on an empty stack, without
+/// running it. Diagnostics are sorted by instruction index.
+///
+/// Computed jumps can land anywhere, so the analysis does not follow them
+/// and skips the unreachable code check for programs that have any.
+pub fn verify(code: &[Op]) -> Vec<Diagnostic> {
+    let mut res = vec![];
+    let mut marks = HashMap::new();
+    for (index, op) in code.iter().enumerate() {
+        if let Op::Mark(id) = op {
+            if marks.insert(id.as_str(), index).is_some() {
+                res.push(Diagnostic {
+                    index,
+                    kind: DiagnosticKind::DuplicateMark(id.clone()),
+                });
+            }
+        }
+        if let Some(reg) = illegal_register(op) {
+            res.push(Diagnostic {
+                index,
+                kind: DiagnosticKind::IllegalRegister(reg),
+            });
+        }
+    }
+
+    // stack depth on entry of every instruction reachable from the start
+    let mut depths = vec![None; code.len()];
+    let mut reported = vec![false; code.len()];
+    let mut queue = vec![(0, 0)];
+    while let Some((index, depth)) = queue.pop() {
+        let Some(op) = code.get(index) else {
+            continue;
+        };
+        match depths[index] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                if !reported[index] {
+                    reported[index] = true;
+                    res.push(Diagnostic {
+                        index,
+                        kind: DiagnosticKind::InconsistentStackDepth(known, depth),
+                    });
+                }
+                continue;
+            }
+            None => depths[index] = Some(depth),
+        }
+        let depth = match op {
+            Op::Push(_) => depth + 1,
+            // keep going as if the pop had worked, so that what follows is
+            // still checked and not taken for unreachable
+            Op::Pop(_) if depth == 0 => {
+                res.push(Diagnostic {
+                    index,
+                    kind: DiagnosticKind::StackUnderflow,
+                });
+                0
+            }
+            Op::Pop(_) => depth - 1,
+            _ => depth,
+        };
+        // jumps land right after the mark
+        let target = match op.label() {
+            Some(id) if !matches!(op, Op::Mark(_)) => match marks.get(id) {
+                Some(mark) => Some(mark + 1),
+                None => {
+                    res.push(Diagnostic {
+                        index,
+                        kind: DiagnosticKind::NoSuchMark(id.to_string()),
+                    });
+                    None
+                }
+            },
+            _ => None,
+        };
+        if !matches!(op, Op::Goto(_)) && !is_computed_jump(op) {
+            queue.push((index + 1, depth));
+        }
+        if let Some(target) = target {
+            queue.push((target, depth));
+        }
+    }
+
+    // report every run of unreachable instructions once, marks alone do not
+    // count since jumps never land on them
+    let mut in_run = false;
+    let computed = code.iter().any(is_computed_jump);
+    for (index, op) in code.iter().enumerate().filter(|_| !computed) {
+        let unreachable = depths[index].is_none() && !matches!(op, Op::Mark(_));
+        if unreachable && !in_run {
+            res.push(Diagnostic {
+                index,
+                kind: DiagnosticKind::Unreachable,
+            });
+        }
+        in_run = unreachable || in_run && matches!(op, Op::Mark(_));
+    }
+    res.sort_by_key(|x| x.index);
+    res
+}
+
+// any write to `opi` may jump, conditional or not
+fn is_computed_jump(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::Jmp(_)
+            | Op::Pop(Reg::OpPtr)
+            | Op::Add(Reg::OpPtr, _)
+            | Op::Sub(Reg::OpPtr, _)
+            | Op::Mul(Reg::OpPtr, _)
+            | Op::Div(Reg::OpPtr, _)
+            | Op::Mod(Reg::OpPtr, _)
+            | Op::Pow(Reg::OpPtr, _)
+            | Op::IDiv(Reg::OpPtr, _)
+            | Op::Mov(Reg::OpPtr, _)
+            | Op::Read(Reg::OpPtr)
+    )
+}
+
+// `cmp` only holds comparison results, and an instruction index has no length
+fn illegal_register(op: &Op) -> Option<Reg> {
+    match op {
+        Op::Add(Reg::Cmp, _)
+        | Op::Sub(Reg::Cmp, _)
+        | Op::Mul(Reg::Cmp, _)
+        | Op::Div(Reg::Cmp, _)
+        | Op::Mod(Reg::Cmp, _)
+        | Op::Pow(Reg::Cmp, _)
+        | Op::IDiv(Reg::Cmp, _)
+        | Op::Len(Reg::Cmp) => Some(Reg::Cmp),
+        Op::Len(Reg::OpPtr) => Some(Reg::OpPtr),
+        _ => None,
+    }
+}
@@ -2,124 +2,570 @@
 
 use std::{
     cmp::Ordering,
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    iter, mem,
+    rc::Rc,
+    time::Instant,
 };
 
-#[derive(Clone, Copy, Debug)]
+mod threaded;
+
+use crate::{
+    bytecode::{self, Decoder, Encoder},
+    profile::Profile,
+    snapshot::{self, SnapshotError},
+};
+
+/// Runtime value held by registers and the stack.
+///
+/// Arithmetic on two `Int`s stays integral and fails with
+/// `ExecutionError::IntegerOverflow` instead of wrapping; as soon as one side
+/// is a `Float` both are promoted to `f64`. The only exceptions are `/` which
+/// always yields a `Float`, and `^` with a negative integer exponent. `Bool`s
+/// can be moved around and compared, but never take part in arithmetic.
+/// Adding anything to a `Str` concatenates their textual forms, as long as
+/// the result stays within `MAX_STR_LEN` bytes.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
-    Lit(f64),
-    Reg(Reg),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(Rc<str>),
+}
+
+/// Longest string concatenation may produce, so that a loop doubling a
+/// string fails instead of exhausting memory.
+pub const MAX_STR_LEN: usize = 1 << 20;
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(x) => write!(f, "{x}"),
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Bool(x) => write!(f, "{x}"),
+            Value::Str(x) => write!(f, "{x}"),
+        }
+    }
 }
 
 impl TryFrom<&str> for Value {
     type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if let Some(num) = value.parse::<f64>().ok() {
-            Ok(Value::Lit(num))
+        if let Ok(num) = value.parse::<i64>() {
+            Ok(Value::Int(num))
+        } else if let Ok(num) = value.parse::<f64>() {
+            Ok(Value::Float(num))
+        } else if let Ok(b) = value.parse::<bool>() {
+            Ok(Value::Bool(b))
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Value {
+    /// Parses a line of input as `Op::Read` does: an integer or else a float,
+    /// surrounding whitespace ignored.
+    pub fn from_input(line: &str) -> Result<Value, ExecutionError> {
+        let line = line.trim();
+        if let Ok(num) = line.parse::<i64>() {
+            Ok(Value::Int(num))
+        } else if let Ok(num) = line.parse::<f64>() {
+            Ok(Value::Float(num))
+        } else {
+            Err(ExecutionError::InvalidInput)
+        }
+    }
+
+    fn as_float(&self) -> Result<f64, ExecutionError> {
+        match self {
+            Value::Int(x) => Ok(*x as f64),
+            Value::Float(x) => Ok(*x),
+            Value::Bool(_) | Value::Str(_) => Err(ExecutionError::TypeError),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        matches!(self, Value::Int(0)) || *self == Value::Float(0.)
+    }
+
+    fn arith(
+        &self,
+        rhs: &Value,
+        int: fn(i64, i64) -> Option<i64>,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<Value, ExecutionError> {
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(b)) => int(*a, *b)
+                .map(Value::Int)
+                .ok_or(ExecutionError::IntegerOverflow),
+            _ => Ok(Value::Float(float(self.as_float()?, rhs.as_float()?))),
+        }
+    }
+
+    /// Adds two numbers, or concatenates when either side is a string.
+    pub fn add(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        if matches!(self, Value::Str(_)) || matches!(rhs, Value::Str(_)) {
+            let res = format!("{self}{rhs}");
+            if res.len() > MAX_STR_LEN {
+                Err(ExecutionError::StringTooLong)?
+            }
+            return Ok(Value::Str(res.into()));
+        }
+        self.arith(rhs, i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn sub(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        self.arith(rhs, i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn mul(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        self.arith(rhs, i64::checked_mul, |a, b| a * b)
+    }
+
+    pub fn div(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        let (a, b) = (self.as_float()?, rhs.as_float()?);
+        if rhs.is_zero() {
+            Err(ExecutionError::ZeroDivisionError)?
+        }
+        Ok(Value::Float(a / b))
+    }
+
+    /// Floored modulo, so that `a == b * (a // b) + a % b` holds.
+    pub fn rem(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        self.as_float()?;
+        if rhs.is_zero() {
+            Err(ExecutionError::ZeroDivisionError)?
+        }
+        self.arith(
+            rhs,
+            |a, b| {
+                let r = a.checked_rem(b)?;
+                Some(if r != 0 && (r < 0) != (b < 0) {
+                    r + b
+                } else {
+                    r
+                })
+            },
+            |a, b| a - b * (a / b).floor(),
+        )
+    }
+
+    pub fn idiv(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        self.as_float()?;
+        if rhs.is_zero() {
+            Err(ExecutionError::ZeroDivisionError)?
+        }
+        self.arith(
+            rhs,
+            |a, b| {
+                let q = a.checked_div(b)?;
+                Some(if a % b != 0 && (a < 0) != (b < 0) {
+                    q - 1
+                } else {
+                    q
+                })
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+
+    pub fn pow(&self, rhs: &Value) -> Result<Value, ExecutionError> {
+        if self.is_zero() && rhs.as_float()? < 0. {
+            Err(ExecutionError::ZeroDivisionError)?
+        }
+        match (self, rhs) {
+            (Value::Int(a @ -1..=1), Value::Int(b)) if *b >= 0 => {
+                Ok(Value::Int(if *b == 0 || *a == -1 && b % 2 == 0 {
+                    1
+                } else {
+                    *a
+                }))
+            }
+            (Value::Int(a), Value::Int(b)) if *b >= 0 => u32::try_from(*b)
+                .ok()
+                .and_then(|b| a.checked_pow(b))
+                .map(Value::Int)
+                .ok_or(ExecutionError::IntegerOverflow),
+            _ => Ok(Value::Float(self.as_float()?.powf(rhs.as_float()?))),
+        }
+    }
+
+    /// Length of a string in chars.
+    pub fn len(&self) -> Result<Value, ExecutionError> {
+        match self {
+            Value::Str(s) => Ok(Value::Int(s.chars().count() as i64)),
+            _ => Err(ExecutionError::TypeError),
+        }
+    }
+
+    pub fn compare(&self, rhs: &Value) -> Result<Ordering, ExecutionError> {
+        match (self, rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Ok(a.cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+            _ => Ok(self.as_float()?.total_cmp(&rhs.as_float()?)),
+        }
+    }
+}
+
+/// Deduplicated string constants referenced by `Operand::Const`, so that
+/// cloning an `Op` never copies string data.
+#[derive(Default, Debug, Clone)]
+pub struct ConstPool {
+    strings: Vec<Rc<str>>,
+    indexes: HashMap<Rc<str>, usize>,
+}
+
+impl ConstPool {
+    pub fn intern(&mut self, value: &str) -> usize {
+        if let Some(index) = self.indexes.get(value) {
+            return *index;
+        }
+        let value = Rc::<str>::from(value);
+        self.strings.push(value.clone());
+        self.indexes.insert(value, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Rc<str>> {
+        self.strings.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rc<str>> {
+        self.strings.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Moves string literals in `code` into the pool, leaving `Const`
+    /// operands in their place.
+    pub fn intern_literals(&mut self, code: &mut [Op]) {
+        for op in code {
+            for val in op.operands_mut() {
+                if let Operand::Lit(Value::Str(string)) = val {
+                    *val = Operand::Const(self.intern(string));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Operand {
+    Lit(Value),
+    Reg(Reg),
+    Const(usize),
+}
+
+impl TryFrom<&str> for Operand {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some(string) = value.strip_prefix('"') {
+            Ok(Operand::Lit(Value::Str(unquote(string)?.into())))
+        } else if let Ok(lit) = Value::try_from(value) {
+            Ok(Operand::Lit(lit))
+        } else if let Some(index) = value.strip_prefix('$').and_then(|x| x.parse().ok()) {
+            Ok(Operand::Const(index))
         } else if let Ok(reg) = Reg::try_from(value) {
-            Ok(Value::Reg(reg))
+            Ok(Operand::Reg(reg))
         } else {
             Err(())
         }
     }
 }
 
+// the rest of a string operand after the opening quote, with the escapes
+// the tokenizer accepts
+fn unquote(value: &str) -> Result<String, ()> {
+    let mut res = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().is_empty() => return Ok(res),
+            '"' => Err(())?,
+            '\\' => res.push(match chars.next().ok_or(())? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                _ => Err(())?,
+            }),
+            _ => res.push(c),
+        }
+    }
+    Err(())
+}
+
+// splits on whitespace, except inside a string operand
+fn split_operands(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line.trim_start();
+    iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut quoted = false;
+        let mut escaped = false;
+        let end = rest
+            .char_indices()
+            .find(|&(index, c)| {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' if quoted => escaped = true,
+                    '"' if index == 0 => quoted = true,
+                    '"' if quoted => quoted = false,
+                    _ => return !quoted && c.is_whitespace(),
+                }
+                false
+            })
+            .map_or(rest.len(), |(index, _)| index);
+        let (arg, tail) = rest.split_at(end);
+        rest = tail.trim_start();
+        Some(arg)
+    })
+}
+
 #[derive(Clone, Debug)]
 pub enum Op {
-    Push(Value),
+    Push(Operand),
     Pop(Reg),
-    Add(Reg, Value),
-    Sub(Reg, Value),
-    Mul(Reg, Value),
-    Div(Reg, Value),
-    Mov(Reg, Value),
-    Cmp(Value, Value),
+    Add(Reg, Operand),
+    Sub(Reg, Operand),
+    Mul(Reg, Operand),
+    Div(Reg, Operand),
+    Mod(Reg, Operand),
+    Pow(Reg, Operand),
+    IDiv(Reg, Operand),
+    Mov(Reg, Operand),
+    Cmp(Operand, Operand),
+    Len(Reg),
+    Print(Operand),
+    Read(Reg),
     Mark(String),
     Goto(String),
-    GotoEq(String, Value),
+    GotoEq(String, Operand),
+    Jmp(Operand),
 }
 
-impl From<&str> for Op {
-    fn from(value: &str) -> Self {
-        let args = value.split_whitespace().collect::<Vec<_>>();
-        match args[0] {
-            "push" => {
-                let value = Value::try_from(args[1]).expect("Invalid value");
-                Op::Push(value)
-            }
-            "pop" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                Op::Pop(reg)
-            }
-            "add" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::Add(reg, value)
-            }
-            "sub" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::Sub(reg, value)
-            }
-            "mul" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::Mul(reg, value)
-            }
-            "div" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::Div(reg, value)
-            }
-            "mov" => {
-                let reg = Reg::try_from(args[1]).expect("Invalid register");
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::Mov(reg, value)
-            }
-            "cmp" => {
-                let value1 = Value::try_from(args[1]).expect("Invalid value");
-                let value2 = Value::try_from(args[2]).expect("Invalid value");
-                Op::Cmp(value1, value2)
-            }
-            "goto" => {
-                let mark = args[1];
-                assert!(
-                    mark.chars().all(|x| x.is_alphanumeric()),
-                    "Mark should be alphanumeric"
-                );
-                Op::Goto(mark.to_string())
-            }
-            "gotoeq" => {
-                let mark = args[1];
-                assert!(
-                    mark.chars().all(|x| x.is_alphanumeric()),
-                    "Mark should be alphanumeric"
-                );
-                let value = Value::try_from(args[2]).expect("Invalid value");
-                Op::GotoEq(mark.to_string(), value)
+impl TryFrom<&str> for Op {
+    type Error = AsmErrorValue;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut args = split_operands(value);
+        let name = args.next().ok_or(AsmErrorValue::UnknownInstruction)?;
+        let mut arg = || args.next().ok_or(AsmErrorValue::MissingOperand);
+        let reg = |x| Reg::try_from(x).map_err(|()| AsmErrorValue::InvalidRegister);
+        let val = |x| Operand::try_from(x).map_err(|()| AsmErrorValue::InvalidOperand);
+        let mark = |x: &str| {
+            if !x.is_empty() && x.chars().all(|x| x.is_alphanumeric()) {
+                Ok(x.to_string())
+            } else {
+                Err(AsmErrorValue::InvalidMark)
             }
-            _ => {
-                // this can be mark
-                let mut chars = value.chars();
-                assert_eq!(
-                    chars
-                        .next_back()
-                        .expect("Only mark can appear in this pattern"),
-                    ':',
-                    "Mark should end with ':'"
-                );
-                let name = chars.collect::<String>();
-                assert!(
-                    name.chars().all(|x| x.is_alphanumeric()),
-                    "Mark should be alphanumeric"
-                );
-                Op::Mark(name)
+        };
+        let op = match name {
+            "push" => Op::Push(val(arg()?)?),
+            "pop" => Op::Pop(reg(arg()?)?),
+            "add" => Op::Add(reg(arg()?)?, val(arg()?)?),
+            "sub" => Op::Sub(reg(arg()?)?, val(arg()?)?),
+            "mul" => Op::Mul(reg(arg()?)?, val(arg()?)?),
+            "div" => Op::Div(reg(arg()?)?, val(arg()?)?),
+            "mod" => Op::Mod(reg(arg()?)?, val(arg()?)?),
+            "pow" => Op::Pow(reg(arg()?)?, val(arg()?)?),
+            "idiv" => Op::IDiv(reg(arg()?)?, val(arg()?)?),
+            "mov" => Op::Mov(reg(arg()?)?, val(arg()?)?),
+            "len" => Op::Len(reg(arg()?)?),
+            "print" => Op::Print(val(arg()?)?),
+            "read" => Op::Read(reg(arg()?)?),
+            "cmp" => Op::Cmp(val(arg()?)?, val(arg()?)?),
+            "goto" => Op::Goto(mark(arg()?)?),
+            "gotoeq" => Op::GotoEq(mark(arg()?)?, val(arg()?)?),
+            "jmp" => Op::Jmp(val(arg()?)?),
+            // this can be mark
+            _ => match name.strip_suffix(':') {
+                Some(name) => Op::Mark(mark(name)?),
+                None => Err(AsmErrorValue::UnknownInstruction)?,
+            },
+        };
+        if args.next().is_some() {
+            Err(AsmErrorValue::ExtraOperand)?
+        }
+        Ok(op)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Lit(Value::Str(x)) => {
+                write!(f, "\"")?;
+                for c in x.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        '\\' | '"' => write!(f, "\\{c}")?,
+                        _ => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
             }
+            Operand::Lit(x) => write!(f, "{x}"),
+            Operand::Reg(x) => write!(f, "{x}"),
+            Operand::Const(x) => write!(f, "${x}"),
         }
     }
 }
 
+impl Op {
+    /// Mark name defined or jumped to by this instruction.
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Op::Mark(id) | Op::Goto(id) | Op::GotoEq(id, _) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Op::Push(val)
+            | Op::Add(_, val)
+            | Op::Sub(_, val)
+            | Op::Mul(_, val)
+            | Op::Div(_, val)
+            | Op::Mod(_, val)
+            | Op::Pow(_, val)
+            | Op::IDiv(_, val)
+            | Op::Mov(_, val)
+            | Op::Print(val)
+            | Op::GotoEq(_, val)
+            | Op::Jmp(val) => vec![val],
+            Op::Cmp(val1, val2) => vec![val1, val2],
+            Op::Pop(_) | Op::Len(_) | Op::Read(_) | Op::Mark(_) | Op::Goto(_) => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Op::Push(val)
+            | Op::Add(_, val)
+            | Op::Sub(_, val)
+            | Op::Mul(_, val)
+            | Op::Div(_, val)
+            | Op::Mod(_, val)
+            | Op::Pow(_, val)
+            | Op::IDiv(_, val)
+            | Op::Mov(_, val)
+            | Op::Print(val)
+            | Op::GotoEq(_, val)
+            | Op::Jmp(val) => vec![val],
+            Op::Cmp(val1, val2) => vec![val1, val2],
+            Op::Pop(_) | Op::Len(_) | Op::Read(_) | Op::Mark(_) | Op::Goto(_) => vec![],
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Op::Push(..) => "push",
+            Op::Pop(..) => "pop",
+            Op::Add(..) => "add",
+            Op::Sub(..) => "sub",
+            Op::Mul(..) => "mul",
+            Op::Div(..) => "div",
+            Op::Mod(..) => "mod",
+            Op::Pow(..) => "pow",
+            Op::IDiv(..) => "idiv",
+            Op::Mov(..) => "mov",
+            Op::Cmp(..) => "cmp",
+            Op::Len(..) => "len",
+            Op::Print(..) => "print",
+            Op::Read(..) => "read",
+            Op::Mark(..) => "mark",
+            Op::Goto(..) => "goto",
+            Op::GotoEq(..) => "gotoeq",
+            Op::Jmp(..) => "jmp",
+        }
+    }
+}
+
+/// Formats as the assembly `Op::try_from` parses.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Push(val) => write!(f, "push {val}"),
+            Op::Pop(reg) => write!(f, "pop {reg}"),
+            Op::Add(reg, val) => write!(f, "add {reg} {val}"),
+            Op::Sub(reg, val) => write!(f, "sub {reg} {val}"),
+            Op::Mul(reg, val) => write!(f, "mul {reg} {val}"),
+            Op::Div(reg, val) => write!(f, "div {reg} {val}"),
+            Op::Mod(reg, val) => write!(f, "mod {reg} {val}"),
+            Op::Pow(reg, val) => write!(f, "pow {reg} {val}"),
+            Op::IDiv(reg, val) => write!(f, "idiv {reg} {val}"),
+            Op::Mov(reg, val) => write!(f, "mov {reg} {val}"),
+            Op::Cmp(val1, val2) => write!(f, "cmp {val1} {val2}"),
+            Op::Len(reg) => write!(f, "len {reg}"),
+            Op::Print(val) => write!(f, "print {val}"),
+            Op::Read(reg) => write!(f, "read {reg}"),
+            Op::Mark(id) => write!(f, "{id}:"),
+            Op::Goto(id) => write!(f, "goto {id}"),
+            Op::GotoEq(id, val) => write!(f, "gotoeq {id} {val}"),
+            Op::Jmp(val) => write!(f, "jmp {val}"),
+        }
+    }
+}
+
+/// Parses a program, one instruction per line. Blank lines and lines
+/// starting with `;` are skipped. String operands are quoted and take the
+/// same escapes as the tokenizer.
+pub fn assemble(src: &str) -> Result<Vec<Op>, AsmError> {
+    src.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, x)| !x.is_empty() && !x.starts_with(';'))
+        .map(|(index, line)| {
+            Op::try_from(line).map_err(|value| AsmError {
+                line: index + 1,
+                value,
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub value: AsmErrorValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsmErrorValue {
+    UnknownInstruction,
+    MissingOperand,
+    ExtraOperand,
+    InvalidRegister,
+    InvalidOperand,
+    InvalidMark,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Reg {
     AX,
@@ -144,44 +590,274 @@ impl TryFrom<&str> for Reg {
     }
 }
 
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reg::AX => "ax",
+            Reg::BX => "bx",
+            Reg::CX => "cx",
+            Reg::Cmp => "cmp",
+            Reg::OpPtr => "opi",
+        })
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Regs {
-    ax: f64,
-    bx: f64,
-    cx: f64,
-    cmp: f64,
+    ax: Value,
+    bx: Value,
+    cx: Value,
+    cmp: Value,
     opptr: usize,
 }
 
 impl Regs {
-    fn resolve(&self, reg: Reg) -> &f64 {
+    /// Value of a register, `Reg::OpPtr` reads as the index of the
+    /// instruction being executed.
+    pub fn get(&self, reg: Reg) -> Value {
         match reg {
-            Reg::AX => &self.ax,
-            Reg::BX => &self.bx,
-            Reg::CX => &self.cx,
-            Reg::Cmp => &self.cmp,
-            Reg::OpPtr => panic!("ты шо дурак"),
+            Reg::AX => self.ax.clone(),
+            Reg::BX => self.bx.clone(),
+            Reg::CX => self.cx.clone(),
+            Reg::Cmp => self.cmp.clone(),
+            Reg::OpPtr => Value::Int(self.opptr as i64),
         }
     }
 
-    fn resolve_mut(&mut self, reg: Reg) -> &mut f64 {
+    // `None` for `Reg::OpPtr`, which is not a plain value
+    fn slot_mut(&mut self, reg: Reg) -> Option<&mut Value> {
         match reg {
-            Reg::AX => &mut self.ax,
-            Reg::BX => &mut self.bx,
-            Reg::CX => &mut self.cx,
-            Reg::Cmp => &mut self.cmp,
-            Reg::OpPtr => panic!("ты шо дурак"),
+            Reg::AX => Some(&mut self.ax),
+            Reg::BX => Some(&mut self.bx),
+            Reg::CX => Some(&mut self.cx),
+            Reg::Cmp => Some(&mut self.cmp),
+            Reg::OpPtr => None,
         }
     }
 }
 
+/// Sink `Op::Print` writes to, stdout by default.
+pub struct Output(Box<dyn Write>);
+
+impl Default for Output {
+    fn default() -> Self {
+        Output(Box::new(io::stdout()))
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+/// Lines `Op::Read` parses numbers from, stdin by default.
+pub struct Input(Box<dyn Iterator<Item = io::Result<String>>>);
+
+impl Default for Input {
+    fn default() -> Self {
+        // stdin is locked per line so the host can keep reading it between runs
+        Input(Box::new(iter::from_fn(|| {
+            let mut buf = String::new();
+            match io::stdin().read_line(&mut buf) {
+                Ok(0) => None,
+                Ok(_) => Some(Ok(buf)),
+                Err(err) => Some(Err(err)),
+            }
+        })))
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Input")
+    }
+}
+
+/// Hooks called around every instruction the VM executes, see
+/// `crate::trace::TextTracer`.
+pub trait Tracer {
+    fn before_op(&mut self, _vm: &VM, _op: &Op) {}
+    fn after_op(&mut self, _vm: &VM, _op: &Op) {}
+}
+
+#[derive(Default)]
+struct TracerHook(Option<Box<dyn Tracer>>);
+
+impl fmt::Debug for TracerHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TracerHook")
+    }
+}
+
+type BinaryFn = fn(&Value, &Value) -> Result<Value, ExecutionError>;
+
+/// `Op` as the VM executes it: labels are resolved to the index a jump
+/// continues at, `None` if there is no such mark, and arithmetic is reduced
+/// to the function it applies.
+#[derive(Clone, Debug)]
+enum Instr {
+    Push(Operand),
+    Pop(Reg),
+    Apply(Reg, Operand, BinaryFn),
+    Mov(Reg, Operand),
+    Cmp(Operand, Operand),
+    Len(Reg),
+    Print(Operand),
+    Read(Reg),
+    Nop,
+    Goto(Option<usize>),
+    GotoEq(Option<usize>, Operand),
+    Jmp(Operand),
+}
+
+impl Instr {
+    fn lower(op: &Op, marks: &HashMap<String, usize>) -> Instr {
+        let target = |id: &String| marks.get(id).copied();
+        match op.clone() {
+            Op::Push(val) => Instr::Push(val),
+            Op::Pop(reg) => Instr::Pop(reg),
+            Op::Add(reg, val) => Instr::Apply(reg, val, Value::add),
+            Op::Sub(reg, val) => Instr::Apply(reg, val, Value::sub),
+            Op::Mul(reg, val) => Instr::Apply(reg, val, Value::mul),
+            Op::Div(reg, val) => Instr::Apply(reg, val, Value::div),
+            Op::Mod(reg, val) => Instr::Apply(reg, val, Value::rem),
+            Op::Pow(reg, val) => Instr::Apply(reg, val, Value::pow),
+            Op::IDiv(reg, val) => Instr::Apply(reg, val, Value::idiv),
+            Op::Mov(reg, val) => Instr::Mov(reg, val),
+            Op::Cmp(val1, val2) => Instr::Cmp(val1, val2),
+            Op::Len(reg) => Instr::Len(reg),
+            Op::Print(val) => Instr::Print(val),
+            Op::Read(reg) => Instr::Read(reg),
+            Op::Mark(_) => Instr::Nop,
+            Op::Goto(id) => Instr::Goto(target(&id)),
+            Op::GotoEq(id, val) => Instr::GotoEq(target(&id), val),
+            Op::Jmp(val) => Instr::Jmp(val),
+        }
+    }
+}
+
+/// Marks defined so far and the jumps still waiting for theirs, so that
+/// code loaded piece by piece is only lowered once. A mark defined more than
+/// once resolves to its first definition, which makes only the waiting
+/// jumps change when more code comes.
+#[derive(Default, Debug)]
+struct Linker {
+    marks: HashMap<String, usize>,
+    unresolved: Vec<usize>,
+}
+
+impl Linker {
+    /// Lowers `code[start..]`, and relowers the earlier jumps it defines
+    /// the marks for, with their indexes.
+    fn link(&mut self, code: &[Op], start: usize) -> (Vec<Instr>, Vec<(usize, Instr)>) {
+        for (index, op) in code.iter().enumerate().skip(start) {
+            if let Op::Mark(id) = op {
+                // jumps land right after the mark
+                self.marks.entry(id.clone()).or_insert(index + 1);
+            }
+        }
+        let mut relinked = vec![];
+        self.unresolved.retain(|&index| {
+            let found = code[index]
+                .label()
+                .is_some_and(|id| self.marks.contains_key(id));
+            if found {
+                relinked.push((index, Instr::lower(&code[index], &self.marks)));
+            }
+            !found
+        });
+        let added = code[start..]
+            .iter()
+            .enumerate()
+            .map(|(offset, op)| {
+                let instr = Instr::lower(op, &self.marks);
+                if let Instr::Goto(None) | Instr::GotoEq(None, _) = instr {
+                    self.unresolved.push(start + offset);
+                }
+                instr
+            })
+            .collect();
+        (added, relinked)
+    }
+}
+
+/// Engine that runs the loaded code.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub enum Backend {
+    /// Matches on every instruction it executes, the reference implementation.
+    #[default]
+    Interpreter,
+    /// Turns every instruction into a closure once at load time, which pays
+    /// off in hot arithmetic loops.
+    Closures,
+}
+
+enum Program {
+    Interpreted(Vec<Instr>),
+    Threaded(Vec<threaded::Closure>),
+}
+
+impl Program {
+    fn new(backend: Backend) -> Self {
+        match backend {
+            Backend::Interpreter => Program::Interpreted(vec![]),
+            Backend::Closures => Program::Threaded(vec![]),
+        }
+    }
+
+    fn extend(&mut self, instrs: Vec<Instr>) {
+        match self {
+            Program::Interpreted(program) => program.extend(instrs),
+            Program::Threaded(program) => program.extend(threaded::compile(instrs)),
+        }
+    }
+
+    fn replace(&mut self, index: usize, instr: Instr) {
+        match self {
+            Program::Interpreted(program) => program[index] = instr,
+            Program::Threaded(program) => program[index] = threaded::compile_instr(instr),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Program::Interpreted(program) => program.len(),
+            Program::Threaded(program) => program.len(),
+        }
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Program::Interpreted(vec![])
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Program")
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct VM {
-    code: VecDeque<Op>,
-    stack: VecDeque<f64>,
+    code: Vec<Op>,
+    // `code` compiled for execution, extended as code is loaded
+    program: Program,
+    linker: Linker,
+    backend: Backend,
+    stack: Vec<Value>,
     stack_size: Option<usize>,
-    marks: HashMap<String, usize>,
     regs: Regs,
+    consts: ConstPool,
+    output: Output,
+    input: Input,
+    fuel: Option<u64>,
+    tracer: TracerHook,
+    profile: Option<Profile>,
+    // where the instruction being executed jumps to, if anywhere
+    jump: Option<usize>,
 }
 
 impl VM {
@@ -192,87 +868,352 @@ impl VM {
         }
     }
 
+    pub fn with_backend(backend: Backend) -> Self {
+        Self {
+            backend,
+            program: Program::new(backend),
+            ..Default::default()
+        }
+    }
+
+    /// Switches the engine, recompiling the loaded code for it.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.recompile();
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        Self {
+            output: Output(output),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Output(output);
+    }
+
+    pub fn with_input(input: Box<dyn Iterator<Item = io::Result<String>>>) -> Self {
+        Self {
+            input: Input(input),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn Iterator<Item = io::Result<String>>>) {
+        self.input = Input(input);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = TracerHook(tracer);
+    }
+
+    /// Starts counting and timing every executed instruction, discarding
+    /// whatever was collected before.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Limits how many more instructions `exec` may run, `None` for no limit.
+    /// Running dry stops with `ExecutionError::OutOfFuel` before the next
+    /// instruction, so `exec` continues from it once fuel is added.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Runs like `exec` for at most `max_steps` instructions. Fuel set with
+    /// `set_fuel` still applies, and what this call used is taken off it.
+    pub fn exec_with_limit(&mut self, max_steps: u64) -> VMResult {
+        let fuel = self.fuel;
+        let limit = fuel.map_or(max_steps, |fuel| fuel.min(max_steps));
+        self.fuel = Some(limit);
+        let res = self.exec();
+        let used = limit - self.fuel.unwrap_or(0);
+        self.fuel = fuel.map(|fuel| fuel - used);
+        res
+    }
+
+    /// Appends instructions to the program. Labels may refer to marks loaded
+    /// before or after them.
     pub fn load<I: IntoIterator<Item = Op>>(&mut self, code: I) {
+        let start = self.code.len();
         self.code.extend(code);
+        self.compile_from(start);
     }
 
-    pub fn exec(&mut self) -> VMResult {
-        loop {
-            let op = if let Some(val) = self.code.get(self.regs.opptr).cloned() {
-                val
-            } else {
-                return Ok(());
-            };
-            match op {
-                Op::Push(val) => {
-                    if let Some(stack_size) = self.stack_size {
-                        if self.stack.len() == stack_size {
-                            Err(ExecutionError::StackOverflow)?;
-                        }
-                    }
-                    self.stack.push_back(self.retrieve_value(val));
-                }
-                Op::Pop(reg) => {
-                    if let Some(val) = self.stack.pop_back() {
-                        *self.regs.resolve_mut(reg) = val;
-                    } else {
-                        Err(ExecutionError::EmptyStack)?
-                    }
+    // only what was added is compiled, plus the jumps waiting for marks it
+    // brought along
+    fn compile_from(&mut self, start: usize) {
+        let (added, relinked) = self.linker.link(&self.code, start);
+        self.program.extend(added);
+        for (index, instr) in relinked {
+            self.program.replace(index, instr);
+        }
+    }
+
+    fn recompile(&mut self) {
+        self.program = Program::new(self.backend);
+        self.linker = Linker::default();
+        self.compile_from(0);
+    }
+
+    /// Loads code whose `Operand::Const`s index into `consts` instead of the
+    /// VM's own pool, such as a decoded bytecode file.
+    pub fn load_linked<I: IntoIterator<Item = Op>>(&mut self, code: I, consts: &ConstPool) {
+        let indexes = consts
+            .iter()
+            .map(|x| self.consts.intern(x))
+            .collect::<Vec<_>>();
+        self.load(code.into_iter().map(|mut op| {
+            for val in op.operands_mut() {
+                if let Operand::Const(index) = val {
+                    *index = indexes.get(*index).copied().unwrap_or(usize::MAX);
                 }
-                Op::Add(reg, val) => *self.regs.resolve_mut(reg) += self.retrieve_value(val),
-                Op::Sub(reg, val) => *self.regs.resolve_mut(reg) -= self.retrieve_value(val),
-                Op::Mul(reg, val) => *self.regs.resolve_mut(reg) *= self.retrieve_value(val),
-                Op::Div(reg, val) => {
-                    let x = self.retrieve_value(val);
-                    if x == 0. {
-                        Err(ExecutionError::ZeroDivisionError)?;
+            }
+            op
+        }));
+    }
+
+    pub fn exec(&mut self) -> VMResult {
+        // the program is moved out for the run so instructions can be
+        // borrowed while the rest of the VM is mutated
+        let program = mem::take(&mut self.program);
+        let res = loop {
+            match self.step_in(&program) {
+                StepResult::Continued => {}
+                StepResult::Halted => break Ok(()),
+                StepResult::Error(err) => break Err(err),
+            }
+        };
+        self.program = program;
+        res
+    }
+
+    /// Executes exactly one instruction. `Halted` means there was nothing left
+    /// to run; on `Error` the failed instruction stays current.
+    pub fn step(&mut self) -> StepResult {
+        let program = mem::take(&mut self.program);
+        let res = self.step_in(&program);
+        self.program = program;
+        res
+    }
+
+    fn step_in(&mut self, program: &Program) -> StepResult {
+        let index = self.regs.opptr;
+        if index >= program.len() {
+            return StepResult::Halted;
+        }
+        let res = if let Some(mut tracer) = self.tracer.0.take() {
+            tracer.before_op(self, &self.code[index]);
+            let res = self.exec_timed(program, index);
+            tracer.after_op(self, &self.code[index]);
+            self.tracer.0 = Some(tracer);
+            res
+        } else {
+            self.exec_timed(program, index)
+        };
+        match res {
+            Ok(()) => StepResult::Continued,
+            Err(err) => StepResult::Error(err),
+        }
+    }
+
+    pub fn opptr(&self) -> usize {
+        self.regs.opptr
+    }
+
+    /// Instruction the next `step` is going to execute.
+    pub fn current_op(&self) -> Option<&Op> {
+        self.code.get(self.regs.opptr)
+    }
+
+    pub fn code(&self) -> &[Op] {
+        &self.code
+    }
+
+    // the time spent in the tracer is left out, and an instruction that
+    // fails is counted like any other, only running out of fuel means it
+    // never ran at all
+    fn exec_timed(&mut self, program: &Program, index: usize) -> VMResult {
+        let start = self.profile.is_some().then(Instant::now);
+        let res = self.exec_at(program, index);
+        if let (Some(profile), Some(start)) = (self.profile.as_mut(), start) {
+            if res != Err(ExecutionError::OutOfFuel) {
+                profile.record(index, start.elapsed());
+            }
+        }
+        res
+    }
+
+    fn exec_at(&mut self, program: &Program, index: usize) -> VMResult {
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                Err(ExecutionError::OutOfFuel)?
+            }
+            *fuel -= 1;
+        }
+        match program {
+            Program::Interpreted(program) => self.exec_instr(&program[index])?,
+            Program::Threaded(program) => program[index](self)?,
+        }
+        match self.jump.take() {
+            Some(index) => self.regs.opptr = index,
+            None => self.regs.opptr += 1,
+        }
+        Ok(())
+    }
+
+    fn exec_instr(&mut self, instr: &Instr) -> VMResult {
+        match instr {
+            Instr::Push(val) => {
+                if let Some(stack_size) = self.stack_size {
+                    if self.stack.len() == stack_size {
+                        Err(ExecutionError::StackOverflow)?;
                     }
-                    *self.regs.resolve_mut(reg) /= x;
                 }
-                Op::Mov(reg, val) => *self.regs.resolve_mut(reg) = self.retrieve_value(val),
-                Op::Cmp(val1, val2) => {
-                    let ord = self
-                        .retrieve_value(val1)
-                        .total_cmp(&self.retrieve_value(val2));
-                    self.regs.cmp = match ord {
-                        Ordering::Less => -1.,
-                        Ordering::Equal => 0.,
-                        Ordering::Greater => 1.,
-                    }
+                self.stack.push(self.retrieve_value(val)?);
+            }
+            Instr::Pop(reg) => {
+                if let Some(val) = self.stack.pop() {
+                    self.write(*reg, val)?;
+                } else {
+                    Err(ExecutionError::EmptyStack)?
                 }
-                Op::Mark(id) => drop(self.marks.insert(id, self.regs.opptr)),
-                Op::Goto(id) => self.goto(id)?,
-                Op::GotoEq(id, val) => {
-                    if self.retrieve_value(val) == self.regs.cmp {
-                        self.goto(id)?
-                    }
+            }
+            Instr::Apply(reg, val, f) => {
+                let x = self.retrieve_value(val)?;
+                self.write(*reg, f(&self.regs.get(*reg), &x)?)?
+            }
+            Instr::Mov(reg, val) => self.write(*reg, self.retrieve_value(val)?)?,
+            Instr::Cmp(val1, val2) => {
+                let ord = self
+                    .retrieve_value(val1)?
+                    .compare(&self.retrieve_value(val2)?)?;
+                self.regs.cmp = Value::Int(ord as i64)
+            }
+            Instr::Len(reg) => self.write(*reg, self.regs.get(*reg).len()?)?,
+            Instr::Print(val) => {
+                let val = self.retrieve_value(val)?;
+                writeln!(self.output.0, "{val}")
+                    .and_then(|_| self.output.0.flush())
+                    .map_err(|err| ExecutionError::OutputError(err.kind()))?
+            }
+            Instr::Read(reg) => {
+                let line = self
+                    .input
+                    .0
+                    .next()
+                    .ok_or(ExecutionError::EndOfInput)?
+                    .map_err(|err| ExecutionError::InputError(err.kind()))?;
+                self.write(*reg, Value::from_input(&line)?)?
+            }
+            Instr::Nop => {}
+            Instr::Goto(target) => self.jump = Some(target.ok_or(ExecutionError::NoSuchMark)?),
+            Instr::GotoEq(target, val) => {
+                if self.retrieve_value(val)?.compare(&self.regs.cmp)? == Ordering::Equal {
+                    self.jump = Some(target.ok_or(ExecutionError::NoSuchMark)?)
                 }
             }
-            self.regs.opptr += 1;
+            Instr::Jmp(val) => self.write(Reg::OpPtr, self.retrieve_value(val)?)?,
         }
+        Ok(())
+    }
+
+    /// Serializes the machine state: code, stack, registers,
+    /// constants and remaining fuel. Host-side configuration such as the
+    /// input, output and tracer is not part of it.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        e.bytes(&bytecode::encode(&self.code, &self.consts));
+        e.usize(self.stack.len());
+        self.stack.iter().for_each(|val| e.value(val));
+        e.option_usize(self.stack_size);
+        for val in [&self.regs.ax, &self.regs.bx, &self.regs.cx, &self.regs.cmp] {
+            e.value(val);
+        }
+        e.usize(self.regs.opptr);
+        e.option_u64(self.fuel);
+        snapshot::seal(e.into_inner())
+    }
+
+    /// Replaces the machine state with one saved by `snapshot`, leaving the
+    /// VM untouched if the data is rejected.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut d = Decoder::new(snapshot::open(data)?);
+        let (code, consts) = bytecode::decode(d.bytes()?)?;
+        let stack = d.list(Decoder::value)?;
+        let stack_size = d.option_usize()?;
+        let regs = Regs {
+            ax: d.value()?,
+            bx: d.value()?,
+            cx: d.value()?,
+            cmp: d.value()?,
+            opptr: d.usize()?,
+        };
+        let fuel = d.option_u64()?;
+        d.finish()?;
+        self.code = code;
+        self.recompile();
+        self.stack = stack;
+        self.stack_size = stack_size;
+        self.regs = regs;
+        self.consts = consts;
+        self.fuel = fuel;
+        Ok(())
     }
 
     pub fn regs(&self) -> &Regs {
         &self.regs
     }
 
-    pub fn stack(&self) -> &VecDeque<f64> {
+    pub fn stack(&self) -> &[Value] {
         &self.stack
     }
 
-    fn goto(&mut self, id: String) -> VMResult {
-        if let Some(index) = self.marks.get(&id).cloned() {
-            self.regs.opptr = index;
-            Ok(())
-        } else {
-            Err(ExecutionError::NoSuchMark)?
+    pub fn consts(&self) -> &ConstPool {
+        &self.consts
+    }
+
+    pub fn consts_mut(&mut self) -> &mut ConstPool {
+        &mut self.consts
+    }
+
+    // writing `opi` jumps to the instruction with that index
+    fn write(&mut self, reg: Reg, val: Value) -> VMResult {
+        match self.regs.slot_mut(reg) {
+            Some(slot) => *slot = val,
+            None => match val {
+                Value::Int(index) if (0..self.code.len() as i64).contains(&index) => {
+                    self.jump = Some(index as usize)
+                }
+                Value::Int(_) => Err(ExecutionError::InvalidJumpTarget)?,
+                _ => Err(ExecutionError::TypeError)?,
+            },
         }
+        Ok(())
     }
 
-    fn retrieve_value(&self, val: Value) -> f64 {
+    fn retrieve_value(&self, val: &Operand) -> Result<Value, ExecutionError> {
         match val {
-            Value::Lit(lit) => lit,
-            Value::Reg(reg) => *self.regs.resolve(reg),
+            Operand::Lit(lit) => Ok(lit.clone()),
+            Operand::Reg(reg) => Ok(self.regs.get(*reg)),
+            Operand::Const(index) => self
+                .consts
+                .get(*index)
+                .map(|x| Value::Str(x.clone()))
+                .ok_or(ExecutionError::NoSuchConstant),
         }
     }
 }
@@ -280,9 +1221,26 @@ impl VM {
 pub type VMResult = Result<(), ExecutionError>;
 
 #[derive(Clone, Copy, Debug)]
+pub enum StepResult {
+    Continued,
+    Halted,
+    Error(ExecutionError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ExecutionError {
     EmptyStack,
     StackOverflow,
     ZeroDivisionError,
     NoSuchMark,
+    InvalidJumpTarget,
+    IntegerOverflow,
+    TypeError,
+    StringTooLong,
+    NoSuchConstant,
+    OutputError(io::ErrorKind),
+    InputError(io::ErrorKind),
+    InvalidInput,
+    EndOfInput,
+    OutOfFuel,
 }
@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+
+use super::{ExecutionError, Instr, Operand, Reg, VMResult, Value, VM};
+
+pub(super) type Closure = Box<dyn Fn(&mut VM) -> VMResult>;
+
+/// Compiles every instruction into a closure with its operands already
+/// picked apart, so running it skips the dispatch `VM::exec_instr` does.
+/// The combinations that are not worth specializing, like io or writes to
+/// `opi`, call back into the interpreter.
+pub(super) fn compile(program: Vec<Instr>) -> Vec<Closure> {
+    program.into_iter().map(compile_instr).collect()
+}
+
+pub(super) fn compile_instr(instr: Instr) -> Closure {
+    match instr {
+        Instr::Push(Operand::Lit(x)) => Box::new(move |vm| {
+            if vm.stack_size == Some(vm.stack.len()) {
+                Err(ExecutionError::StackOverflow)?
+            }
+            vm.stack.push(x.clone());
+            Ok(())
+        }),
+        Instr::Push(Operand::Reg(reg)) => Box::new(move |vm| {
+            if vm.stack_size == Some(vm.stack.len()) {
+                Err(ExecutionError::StackOverflow)?
+            }
+            vm.stack.push(vm.regs.get(reg));
+            Ok(())
+        }),
+        Instr::Pop(reg) if !matches!(reg, Reg::OpPtr) => Box::new(move |vm| {
+            let val = vm.stack.pop().ok_or(ExecutionError::EmptyStack)?;
+            *vm.regs.slot_mut(reg).unwrap() = val;
+            Ok(())
+        }),
+        Instr::Apply(reg, Operand::Lit(x), f) if !matches!(reg, Reg::OpPtr) => {
+            Box::new(move |vm| {
+                let slot = vm.regs.slot_mut(reg).unwrap();
+                *slot = f(slot, &x)?;
+                Ok(())
+            })
+        }
+        Instr::Apply(reg, Operand::Reg(src), f) if !matches!(reg, Reg::OpPtr) => {
+            Box::new(move |vm| {
+                let x = vm.regs.get(src);
+                let slot = vm.regs.slot_mut(reg).unwrap();
+                *slot = f(slot, &x)?;
+                Ok(())
+            })
+        }
+        Instr::Mov(reg, Operand::Lit(x)) if !matches!(reg, Reg::OpPtr) => Box::new(move |vm| {
+            *vm.regs.slot_mut(reg).unwrap() = x.clone();
+            Ok(())
+        }),
+        Instr::Mov(reg, Operand::Reg(src)) if !matches!(reg, Reg::OpPtr) => Box::new(move |vm| {
+            *vm.regs.slot_mut(reg).unwrap() = vm.regs.get(src);
+            Ok(())
+        }),
+        Instr::Cmp(Operand::Reg(reg), Operand::Lit(x)) => Box::new(move |vm| {
+            vm.regs.cmp = Value::Int(vm.regs.get(reg).compare(&x)? as i64);
+            Ok(())
+        }),
+        Instr::Nop => Box::new(|_| Ok(())),
+        Instr::Goto(Some(target)) => Box::new(move |vm| {
+            vm.jump = Some(target);
+            Ok(())
+        }),
+        Instr::GotoEq(Some(target), Operand::Lit(x)) => Box::new(move |vm| {
+            if x.compare(&vm.regs.cmp)? == Ordering::Equal {
+                vm.jump = Some(target);
+            }
+            Ok(())
+        }),
+        instr => Box::new(move |vm| vm.exec_instr(&instr)),
+    }
+}
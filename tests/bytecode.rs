@@ -0,0 +1,39 @@
+use std::{env, fs, process::Command};
+
+use vm::bytecode;
+
+#[test]
+fn compiled_file_keeps_strings() {
+    let dir = env::temp_dir().join(format!("vm-bytecode-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let asm = dir.join("hello.asm");
+    let vmb = dir.join("hello.vmb");
+    fs::write(
+        &asm,
+        "mov ax \"hello, \\\"world\\\"\"\nprint ax\nlen ax\nprint ax\n",
+    )
+    .unwrap();
+
+    let vm = env!("CARGO_BIN_EXE_vm");
+    let status = Command::new(vm)
+        .args(["compile", "-o"])
+        .args([&vmb, &asm])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let (code, consts) = bytecode::decode(&fs::read(&vmb).unwrap()).unwrap();
+    assert_eq!(code[0].to_string(), "mov ax $0");
+    assert_eq!(
+        consts.iter().map(|x| &**x).collect::<Vec<_>>(),
+        ["hello, \"world\""]
+    );
+
+    let output = Command::new(vm).arg("run").arg(&vmb).output().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "hello, \"world\"\n14\n"
+    );
+}
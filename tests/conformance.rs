@@ -0,0 +1,466 @@
+use std::io;
+
+use common::Buffer;
+use vm::vm::{assemble, Backend, ExecutionError, Op, Reg, VM};
+
+mod common;
+
+struct Case {
+    name: &'static str,
+    consts: &'static [&'static str],
+    input: &'static [&'static str],
+    src: &'static str,
+    // `Debug` of the result and everything printed
+    result: &'static str,
+    output: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "integer arithmetic",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax 7
+            add ax 5
+            mul ax 3
+            sub ax 1
+            idiv ax 4
+            mod ax 5
+            pow ax 3
+            print ax
+            mov bx -7
+            mod bx 3
+            print bx
+            mov bx -7
+            idiv bx 2
+            print bx
+        ",
+        result: "Ok(())",
+        output: "27\n2\n-4\n",
+    },
+    Case {
+        name: "float promotion",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax 1
+            div ax 4
+            print ax
+            add ax 2
+            print ax
+            mov bx 2
+            pow bx -1
+            print bx
+            mov cx 2.5
+            mul cx bx
+            print cx
+        ",
+        result: "Ok(())",
+        output: "0.25\n2.25\n0.5\n1.25\n",
+    },
+    Case {
+        name: "integer overflow",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax 9223372036854775807
+            print ax
+            add ax 1
+            print ax
+        ",
+        result: "Err(IntegerOverflow)",
+        output: "9223372036854775807\n",
+    },
+    Case {
+        name: "division by zero",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax 1
+            mod ax 0
+        ",
+        result: "Err(ZeroDivisionError)",
+        output: "",
+    },
+    Case {
+        name: "bool arithmetic",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax true
+            cmp ax true
+            print cmp
+            add ax 1
+        ",
+        result: "Err(TypeError)",
+        output: "0\n",
+    },
+    Case {
+        name: "strings",
+        consts: &["ab", "cd"],
+        input: &[],
+        src: "
+            push $0
+            pop ax
+            add ax $1
+            add ax 1
+            print ax
+            len ax
+            print ax
+            mov bx $1
+            cmp bx $0
+            print cmp
+        ",
+        result: "Ok(())",
+        output: "abcd1\n5\n1\n",
+    },
+    Case {
+        name: "string literals",
+        consts: &[],
+        input: &[],
+        src: r#"
+            mov ax "a b"
+            add ax "\t\"c\\"
+            print ax
+            len ax
+            print ax
+        "#,
+        result: "Ok(())",
+        output: "a b\t\"c\\\n7\n",
+    },
+    Case {
+        name: "string length limit",
+        consts: &["ab"],
+        input: &[],
+        src: "
+            mov ax $0
+            loop:
+            add ax ax
+            goto loop
+        ",
+        result: "Err(StringTooLong)",
+        output: "",
+    },
+    Case {
+        name: "missing constant",
+        consts: &["ab"],
+        input: &[],
+        src: "
+            print $0
+            print $1
+        ",
+        result: "Err(NoSuchConstant)",
+        output: "ab\n",
+    },
+    Case {
+        name: "stack",
+        consts: &[],
+        input: &[],
+        src: "
+            push 1
+            push 2.5
+            mov ax 3
+            push ax
+            pop bx
+            pop cx
+            pop ax
+            print ax
+            print bx
+            print cx
+            pop ax
+        ",
+        result: "Err(EmptyStack)",
+        output: "1\n3\n2.5\n",
+    },
+    Case {
+        name: "loop",
+        consts: &[],
+        input: &[],
+        src: "
+            mov cx 0
+            loop:
+            add cx 1
+            push cx
+            cmp cx 5
+            gotoeq loop -1
+            pop ax
+            pop bx
+            print ax
+            print bx
+        ",
+        result: "Ok(())",
+        output: "5\n4\n",
+    },
+    Case {
+        name: "forward jump",
+        consts: &[],
+        input: &[],
+        src: "
+            goto skip
+            print 1
+            skip:
+            print 2
+            goto nowhere
+        ",
+        result: "Err(NoSuchMark)",
+        output: "2\n",
+    },
+    Case {
+        name: "computed jumps",
+        consts: &[],
+        input: &[],
+        src: "
+            mov ax opi
+            add ax 4
+            jmp ax
+            print 1
+            print opi
+            mov bx 8
+            mov opi bx
+            print 2
+            push 10
+            pop opi
+        ",
+        result: "Err(InvalidJumpTarget)",
+        output: "4\n",
+    },
+    Case {
+        name: "jump to a non integer",
+        consts: &[],
+        input: &[],
+        src: "
+            jmp 1.5
+        ",
+        result: "Err(TypeError)",
+        output: "",
+    },
+    Case {
+        name: "read",
+        consts: &[],
+        input: &["4", " 2.5 ", "x"],
+        src: "
+            read ax
+            read bx
+            mul ax bx
+            print ax
+            read cx
+        ",
+        result: "Err(InvalidInput)",
+        output: "10.0\n",
+    },
+    Case {
+        name: "end of input",
+        consts: &[],
+        input: &[],
+        src: "
+            read ax
+        ",
+        result: "Err(EndOfInput)",
+        output: "",
+    },
+    Case {
+        name: "illegal register use",
+        consts: &[],
+        input: &[],
+        src: "
+            add cmp 1
+            len opi
+        ",
+        result: "Err(TypeError)",
+        output: "",
+    },
+];
+
+struct Outcome {
+    result: String,
+    output: String,
+    stack: String,
+    regs: String,
+    opptr: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    stack_size: Option<usize>,
+    fuel: Option<u64>,
+}
+
+fn setup(case: &Case, backend: Backend, limits: Limits, output: Box<dyn io::Write>) -> VM {
+    setup_with(case, backend, limits, output, |vm, code| vm.load(code))
+}
+
+fn setup_with(
+    case: &Case,
+    backend: Backend,
+    limits: Limits,
+    output: Box<dyn io::Write>,
+    load: impl Fn(&mut VM, Vec<Op>),
+) -> VM {
+    let mut vm = match limits.stack_size {
+        Some(size) => VM::with_sized_stack(size),
+        None => VM::default(),
+    };
+    vm.set_backend(backend);
+    vm.set_output(output);
+    let input = case.input.iter().map(|x| Ok(x.to_string()));
+    vm.set_input(Box::new(input.collect::<Vec<_>>().into_iter()));
+    for x in case.consts {
+        vm.consts_mut().intern(x);
+    }
+    load(&mut vm, assemble(case.src).unwrap());
+    vm.set_fuel(limits.fuel);
+    vm
+}
+
+fn run(case: &Case, backend: Backend, limits: Limits) -> Outcome {
+    let output = Buffer::default();
+    let mut vm = setup(case, backend, limits, Box::new(output.clone()));
+    let result = format!("{:?}", vm.exec());
+    let output = output.take();
+    Outcome {
+        result,
+        output,
+        stack: format!("{:?}", vm.stack()),
+        regs: format!("{:?}", vm.regs()),
+        opptr: vm.opptr(),
+    }
+}
+
+fn check(case: &Case, limits: Limits) {
+    let expected = run(case, Backend::Interpreter, limits);
+    let actual = run(case, Backend::Closures, limits);
+    assert_eq!(actual.result, expected.result, "{}", case.name);
+    assert_eq!(actual.output, expected.output, "{}", case.name);
+    assert_eq!(actual.stack, expected.stack, "{}", case.name);
+    assert_eq!(actual.regs, expected.regs, "{}", case.name);
+    assert_eq!(actual.opptr, expected.opptr, "{}", case.name);
+}
+
+#[test]
+fn reference_results() {
+    for case in CASES {
+        let outcome = run(case, Backend::Interpreter, Limits::default());
+        assert_eq!(outcome.result, case.result, "{}", case.name);
+        assert_eq!(outcome.output, case.output, "{}", case.name);
+    }
+}
+
+#[test]
+fn backends_agree() {
+    for case in CASES {
+        check(case, Limits::default());
+    }
+}
+
+#[test]
+fn backends_agree_on_limits() {
+    for case in CASES {
+        for fuel in 0..8 {
+            let fuel = Some(fuel);
+            check(
+                case,
+                Limits {
+                    fuel,
+                    ..Default::default()
+                },
+            );
+        }
+        let stack_size = Some(1);
+        check(
+            case,
+            Limits {
+                stack_size,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+#[test]
+fn backends_agree_stepwise() {
+    for case in CASES {
+        let sink = || Box::new(io::sink());
+        let mut interpreter = setup(case, Backend::Interpreter, Limits::default(), sink());
+        let mut closures = setup(case, Backend::Closures, Limits::default(), sink());
+        loop {
+            let expected = format!("{:?}", interpreter.step());
+            assert_eq!(format!("{:?}", closures.step()), expected, "{}", case.name);
+            assert_eq!(closures.opptr(), interpreter.opptr(), "{}", case.name);
+            if expected != "Continued" {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn loading_in_pieces() {
+    for case in CASES {
+        for backend in [Backend::Interpreter, Backend::Closures] {
+            let expected = run(case, backend, Limits::default());
+            // jumps to marks that are only loaded later have to find them
+            let output = Buffer::default();
+            let mut vm = setup_with(
+                case,
+                backend,
+                Limits::default(),
+                Box::new(output.clone()),
+                |vm, code| code.into_iter().for_each(|op| vm.load([op])),
+            );
+            assert_eq!(format!("{:?}", vm.exec()), expected.result, "{}", case.name);
+            assert_eq!(output.take(), expected.output, "{}", case.name);
+        }
+    }
+}
+
+#[test]
+fn limits_stop_before_an_instruction_and_resume() {
+    for backend in [Backend::Interpreter, Backend::Closures] {
+        let mut vm = VM::with_backend(backend);
+        vm.load(assemble("mov ax 0\nloop:\nadd ax 1\ngoto loop").unwrap());
+        assert_eq!(vm.exec_with_limit(8), Err(ExecutionError::OutOfFuel));
+        // `mov`, the mark, then `add` and `goto` three times
+        assert_eq!(
+            (vm.regs().get(Reg::AX).to_string(), vm.opptr()),
+            ("3".into(), 2)
+        );
+        assert_eq!(vm.exec_with_limit(1), Err(ExecutionError::OutOfFuel));
+        assert_eq!(
+            (vm.regs().get(Reg::AX).to_string(), vm.opptr()),
+            ("4".into(), 3)
+        );
+        assert_eq!(vm.exec_with_limit(0), Err(ExecutionError::OutOfFuel));
+        assert_eq!(
+            (vm.regs().get(Reg::AX).to_string(), vm.opptr()),
+            ("4".into(), 3)
+        );
+
+        let case = CASES.iter().find(|x| x.name == "loop").unwrap();
+        let expected = run(case, backend, Limits::default());
+        let output = Buffer::default();
+        let mut vm = setup(case, backend, Limits::default(), Box::new(output.clone()));
+        assert_eq!(vm.exec_with_limit(5), Err(ExecutionError::OutOfFuel));
+        assert_eq!(format!("{:?}", vm.exec_with_limit(1000)), expected.result);
+        assert_eq!(output.take(), expected.output);
+        assert_eq!(format!("{:?}", vm.stack()), expected.stack);
+        assert_eq!(format!("{:?}", vm.regs()), expected.regs);
+        assert_eq!(vm.opptr(), expected.opptr);
+    }
+}
+
+#[test]
+fn limits_spend_fuel() {
+    let mut vm = VM::default();
+    vm.load(assemble("loop:\ngoto loop").unwrap());
+    vm.set_fuel(Some(100));
+    assert_eq!(vm.exec_with_limit(10), Err(ExecutionError::OutOfFuel));
+    assert_eq!(vm.fuel(), Some(90));
+    // the smaller of the two stops it
+    vm.set_fuel(Some(3));
+    assert_eq!(vm.exec_with_limit(10), Err(ExecutionError::OutOfFuel));
+    assert_eq!(vm.fuel(), Some(0));
+    vm.set_fuel(None);
+    assert_eq!(vm.exec_with_limit(10), Err(ExecutionError::OutOfFuel));
+    assert_eq!(vm.fuel(), None);
+}
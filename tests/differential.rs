@@ -0,0 +1,206 @@
+use std::io;
+
+use common::Buffer;
+use vm::{
+    codegen,
+    eval::eval_program,
+    lexer::{self, Expr, Program},
+    parser::{self, Builtin, OpType},
+    vm::{Backend, ConstPool, VM},
+};
+
+mod common;
+
+const CASES: u64 = 5000;
+const MAX_DEPTH: u32 = 6;
+
+// xorshift64*, plenty for picking expressions
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize].clone()
+    }
+}
+
+// edge cases are overrepresented so that errors show up often
+fn leaf(rng: &mut Rng) -> Expr {
+    match rng.below(10) {
+        0..=3 => Expr::Int(rng.below(7) as i64 - 2),
+        4 => Expr::Int(rng.pick(&[i64::MAX, i64::MIN, 1 << 32, 63, 64])),
+        5 | 6 => Expr::Float(rng.pick(&[0.0, -0.0, 0.5, 2.5, -1.5, 1e308, f64::MIN_POSITIVE])),
+        7 | 8 => Expr::Str(rng.pick(&["", "a", "bc", "ünï"]).to_string()),
+        _ => Expr::Call(Builtin::Read, vec![]),
+    }
+}
+
+fn expr(rng: &mut Rng, depth: u32) -> Expr {
+    if depth == 0 || rng.below(4) == 0 {
+        return leaf(rng);
+    }
+    match rng.below(10) {
+        0 => Expr::Call(Builtin::Len, vec![expr(rng, depth - 1)]),
+        1 => Expr::Call(Builtin::Print, vec![expr(rng, depth - 1)]),
+        2 => Expr::Block(exprs(rng, depth - 1)),
+        _ => {
+            let op = rng.pick(&[
+                OpType::Add,
+                OpType::Sub,
+                OpType::Mul,
+                OpType::Div,
+                OpType::Mod,
+                OpType::Pow,
+                OpType::IDiv,
+            ]);
+            let lhs = expr(rng, depth - 1);
+            let rhs = expr(rng, depth - 1);
+            Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+        }
+    }
+}
+
+fn exprs(rng: &mut Rng, depth: u32) -> Vec<Expr> {
+    let len = rng.below(3) + 1;
+    (0..len).map(|_| expr(rng, depth)).collect()
+}
+
+// a few lines, some not numbers, and running out of them is an error too
+fn input(rng: &mut Rng) -> Vec<String> {
+    let len = rng.below(4);
+    (0..len)
+        .map(|_| {
+            rng.pick(&["0", "3", " -2 ", "0.25", "1e3", "x"])
+                .to_string()
+        })
+        .collect()
+}
+
+fn lines(input: Vec<String>) -> Box<dyn Iterator<Item = io::Result<String>>> {
+    Box::new(input.into_iter().map(Ok))
+}
+
+// the language has no negative literals, so those are spelled as
+// subtractions and multiplications that give exactly the same value
+fn source(expr: &Expr) -> String {
+    match expr {
+        Expr::Int(i64::MIN) => format!("(- (- 0 {}) 1)", i64::MAX),
+        Expr::Int(num) if *num < 0 => format!("(- 0 {})", -num),
+        Expr::Int(num) => num.to_string(),
+        Expr::Float(num) if num.is_sign_negative() => format!("(* (- 0 1.0) {:?})", -num),
+        Expr::Float(num) => format!("{num:?}"),
+        Expr::Str(string) => {
+            let mut res = String::from('"');
+            for c in string.chars() {
+                match c {
+                    '"' => res.push_str("\\\""),
+                    '\\' => res.push_str("\\\\"),
+                    '\n' => res.push_str("\\n"),
+                    _ => res.push(c),
+                }
+            }
+            res.push('"');
+            res
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let op = match op {
+                OpType::Add => "+",
+                OpType::Sub => "-",
+                OpType::Mul => "*",
+                OpType::Div => "/",
+                OpType::Mod => "%",
+                OpType::Pow => "**",
+                OpType::IDiv => "//",
+            };
+            format!("({op} {} {})", source(lhs), source(rhs))
+        }
+        Expr::Call(builtin, args) => {
+            let args = args.iter().map(|x| format!(" {}", source(x)));
+            format!("({}{})", builtin.name(), args.collect::<String>())
+        }
+        Expr::Block(exprs) => {
+            let exprs = exprs.iter().map(source).collect::<Vec<_>>();
+            format!("{{ {} }}", exprs.join("; "))
+        }
+    }
+}
+
+fn reparse(program: &Program) -> Program {
+    let src = program
+        .statements
+        .iter()
+        .map(source)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let tokens = parser::parse(&src).unwrap_or_else(|err| panic!("{src}\n{err:?}"));
+    lexer::lex(tokens).unwrap_or_else(|err| panic!("{src}\n{err:?}"))
+}
+
+// results are compared through `Debug` so that NaNs match each other
+fn direct(program: &Program, input: &[String]) -> (String, String) {
+    let mut output = Buffer::default();
+    let res = eval_program(program, &mut output, &mut lines(input.to_vec()))
+        .map(|x| x.expect("programs are never empty"));
+    (format!("{res:?}"), output.take())
+}
+
+fn compiled(program: &Program, input: &[String], backend: Backend) -> (String, String) {
+    let output = Buffer::default();
+    let mut vm = VM::with_backend(backend);
+    vm.set_output(Box::new(output.clone()));
+    vm.set_input(lines(input.to_vec()));
+    let mut consts = ConstPool::default();
+    let code = codegen::gen(program.clone(), &mut consts);
+    vm.load_linked(code, &consts);
+    let res = vm.exec().map(|()| {
+        assert_eq!(vm.stack().len(), 1, "{program:?}");
+        vm.stack()[0].clone()
+    });
+    (format!("{res:?}"), output.take())
+}
+
+#[test]
+fn vm_matches_evaluator() {
+    let mut errors = 0;
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let program = Program {
+            statements: exprs(&mut rng, MAX_DEPTH),
+        };
+        let input = input(&mut rng);
+        let expected = direct(&program, &input);
+        // every other case goes through its source, to cover the frontend
+        let compiled_program = if seed % 2 == 0 {
+            reparse(&program)
+        } else {
+            program.clone()
+        };
+        for backend in [Backend::Interpreter, Backend::Closures] {
+            let actual = compiled(&compiled_program, &input, backend);
+            assert_eq!(
+                actual, expected,
+                "seed {seed}, {backend:?}\n{program:?}\ninput {input:?}"
+            );
+        }
+        errors += expected.0.starts_with("Err") as u64;
+    }
+    // the generator is useless if one outcome dominates
+    assert!(
+        (CASES / 10..CASES * 9 / 10).contains(&errors),
+        "{errors} of {CASES} cases failed"
+    );
+}
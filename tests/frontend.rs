@@ -0,0 +1,274 @@
+use vm::{
+    lexer::{self, Expr, LexError, LexErrorValue},
+    parser::{self, ParseErrorValue, TokenValue, MAX_ERRORS},
+};
+
+fn lex(src: &str) -> lexer::LexerResult {
+    lexer::lex(parser::parse(src).unwrap())
+}
+
+// the only error, where most tests expect exactly one
+fn lex_error(src: &str) -> LexError {
+    let mut errors = lex(src).unwrap_err();
+    assert_eq!(errors.len(), 1, "{src:?}: {errors:?}");
+    errors.remove(0)
+}
+
+fn statements(src: &str) -> Vec<Expr> {
+    lex(src).unwrap().statements
+}
+
+#[test]
+fn blank_input_is_empty() {
+    for src in ["", " ", "\t", " \t\r\n ", "\u{a0}\u{2003}", ";", " ;\n; "] {
+        assert!(statements(src).is_empty(), "{src:?}");
+    }
+}
+
+#[test]
+fn any_whitespace_separates_tokens() {
+    let ast = statements("\t+ 1\t(*\n2 3) ");
+    assert!(matches!(
+        ast[..],
+        [Expr::Binary(_, ref lhs, ref rhs)]
+            if matches!(**lhs, Expr::Int(1)) && matches!(**rhs, Expr::Binary(..))
+    ));
+}
+
+#[test]
+fn missing_operands_are_errors() {
+    for (src, index) in [("+", 0), ("  * 2", 2), ("len", 0), ("+ 1 (print)", 5)] {
+        let err = lex_error(src);
+        assert!(
+            matches!(err.value, LexErrorValue::InvalidNumberOfArguments),
+            "{src:?}"
+        );
+        assert_eq!(err.token.index, index, "{src:?}");
+    }
+}
+
+#[test]
+fn empty_parentheses_are_errors() {
+    for (src, index) in [("()", 0), ("+ 1 ( )", 4), ("+ ((1)) ()", 8)] {
+        let err = lex_error(src);
+        assert!(
+            matches!(err.value, LexErrorValue::EmptyParenthesis),
+            "{src:?}"
+        );
+        assert_eq!(err.token.index, index, "{src:?}");
+    }
+}
+
+#[test]
+fn leftover_tokens_are_errors() {
+    for (src, index) in [
+        ("1 2 3", 2),
+        ("+ 1 2 5", 6),
+        ("1 + 2 3", 2),
+        ("(1 2)", 3),
+        ("len \"a\" 2", 8),
+        ("(* 2 3) (read)", 9),
+    ] {
+        let err = lex_error(src);
+        assert!(
+            matches!(err.value, LexErrorValue::UnexpectedToken),
+            "{src:?}"
+        );
+        assert_eq!(err.token.index, index, "{src:?}");
+    }
+}
+
+#[test]
+fn unmatched_parentheses_are_errors() {
+    for (src, index) in [
+        (")", 0),
+        ("1 )", 2),
+        ("+ 1 (2))", 7),
+        ("(1", 0),
+        ("( (1)", 0),
+    ] {
+        let err = lex_error(src);
+        assert!(
+            matches!(err.value, LexErrorValue::UnmatchedParenthesis),
+            "{src:?}"
+        );
+        assert_eq!(err.token.index, index, "{src:?}");
+    }
+}
+
+#[test]
+fn tokenizer_picks_up_after_line_breaks_and_parentheses() {
+    let errors = parser::parse("+ 1 $ 2\n(len 1.2.3) @\n\"a\\q\" foo").unwrap_err();
+    let errors = errors
+        .iter()
+        .map(|x| (x.index, format!("{:?}", x.value)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            (4, format!("{:?}", ParseErrorValue::UnexpectedCharacter)),
+            (16, format!("{:?}", ParseErrorValue::MultipleDots)),
+            (20, format!("{:?}", ParseErrorValue::UnexpectedCharacter)),
+            (24, format!("{:?}", ParseErrorValue::InvalidEscape)),
+            (28, format!("{:?}", ParseErrorValue::UnknownIdentifier)),
+        ]
+    );
+}
+
+#[test]
+fn number_literals() {
+    let cases = [
+        ("1e-9", TokenValue::Float(1e-9)),
+        ("6.02E23", TokenValue::Float(6.02e23)),
+        ("1.5e+3", TokenValue::Float(1500.)),
+        ("1e308", TokenValue::Float(1e308)),
+        ("0xff", TokenValue::Int(255)),
+        ("0XFF", TokenValue::Int(255)),
+        ("0b1010", TokenValue::Int(10)),
+        ("1_000_000", TokenValue::Int(1_000_000)),
+        ("0x7fff_ffff_ffff_ffff", TokenValue::Int(i64::MAX)),
+        ("9223372036854775807", TokenValue::Int(i64::MAX)),
+    ];
+    for (src, expected) in cases {
+        let tokens = parser::parse(src).unwrap();
+        assert_eq!(tokens.len(), 1, "{src:?}");
+        assert_eq!(
+            format!("{:?}", tokens[0].value),
+            format!("{expected:?}"),
+            "{src:?}"
+        );
+    }
+}
+
+#[test]
+fn number_literal_errors() {
+    let cases = [
+        ("- 1e", 3, ParseErrorValue::InvalidExponent),
+        ("- 1e+", 3, ParseErrorValue::InvalidExponent),
+        ("- 1e5.5", 5, ParseErrorValue::InvalidExponent),
+        ("- 1e2e3", 5, ParseErrorValue::InvalidExponent),
+        ("- 0xfg", 5, ParseErrorValue::InvalidHexDigit),
+        ("- 0x", 4, ParseErrorValue::InvalidHexDigit),
+        ("- 0b102", 6, ParseErrorValue::InvalidBinaryDigit),
+        ("- 1__0", 3, ParseErrorValue::MisplacedUnderscore),
+        ("- 1_", 3, ParseErrorValue::MisplacedUnderscore),
+        ("- 1_.5", 3, ParseErrorValue::MisplacedUnderscore),
+        ("- 0x_f", 4, ParseErrorValue::MisplacedUnderscore),
+        ("- 9223372036854775808", 2, ParseErrorValue::NumberTooLarge),
+        ("- 0x8000000000000000", 2, ParseErrorValue::NumberTooLarge),
+        ("- 1e400", 2, ParseErrorValue::NumberTooLarge),
+        ("- 1.5E+309", 2, ParseErrorValue::NumberTooLarge),
+    ];
+    for (src, index, value) in cases {
+        let errors = parser::parse(src).unwrap_err();
+        let errors = errors
+            .iter()
+            .map(|x| (x.index, format!("{:?}", x.value)))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [(index, format!("{value:?}"))], "{src:?}");
+    }
+}
+
+#[test]
+fn tokenizer_skips_over_strings() {
+    for src in ["@ \")\" 1", "@ \"a\\\")\" 1\n2", "1.2.3\";\" 1"] {
+        let errors = parser::parse(src).unwrap_err();
+        assert_eq!(errors.len(), 1, "{src:?}: {errors:?}");
+    }
+}
+
+#[test]
+fn parser_picks_up_after_parentheses() {
+    let errors = lex("+ (1 2) (len) 3 )").unwrap_err();
+    let errors = errors
+        .iter()
+        .map(|x| (x.token.index, format!("{:?}", x.value)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            (5, format!("{:?}", LexErrorValue::UnexpectedToken)),
+            (9, format!("{:?}", LexErrorValue::InvalidNumberOfArguments)),
+            (16, format!("{:?}", LexErrorValue::UnmatchedParenthesis)),
+        ]
+    );
+    // a broken group still counts as the argument it was meant to be
+    assert_eq!(lex("print (+ 1)").unwrap_err().len(), 1);
+}
+
+#[test]
+fn errors_are_capped() {
+    let src = "$\n".repeat(MAX_ERRORS * 2);
+    assert_eq!(parser::parse(&src).unwrap_err().len(), MAX_ERRORS);
+    let src = "(1 2) ".repeat(MAX_ERRORS * 2);
+    assert_eq!(lex(&src).unwrap_err().len(), MAX_ERRORS);
+}
+
+#[test]
+fn statements_are_separated_by_semicolons_and_line_breaks() {
+    let ast = statements("1; + 2 3\n\n(len\n\"ab\");");
+    assert!(matches!(
+        ast[..],
+        [Expr::Int(1), Expr::Binary(..), Expr::Call(..)]
+    ));
+}
+
+#[test]
+fn blocks_hold_statements() {
+    let ast = statements("+ 1 {\n print 2; 3\n}\n{ { 4 } }");
+    assert!(matches!(
+        ast[..],
+        [Expr::Binary(_, _, ref rhs), Expr::Block(ref outer)]
+            if matches!(**rhs, Expr::Block(ref inner) if inner.len() == 2)
+                && matches!(outer[..], [Expr::Block(_)])
+    ));
+}
+
+#[test]
+fn block_errors() {
+    for (src, index, value) in [
+        ("{}", 0, LexErrorValue::EmptyBlock),
+        ("+ 1 { ; }", 4, LexErrorValue::EmptyBlock),
+        ("{ 1", 0, LexErrorValue::UnmatchedBrace),
+        ("1 }", 2, LexErrorValue::UnmatchedBrace),
+        ("{ 1 )", 4, LexErrorValue::UnmatchedParenthesis),
+        ("( 1 }", 4, LexErrorValue::UnmatchedBrace),
+        ("(1; 2)", 2, LexErrorValue::UnexpectedToken),
+    ] {
+        let err = lex_error(src);
+        assert_eq!(format!("{:?}", err.value), format!("{value:?}"), "{src:?}");
+        assert_eq!(err.token.index, index, "{src:?}");
+    }
+}
+
+#[test]
+fn parser_picks_up_after_statements() {
+    let errors = lex("+ 1; 2\nlen; { 1 2 }; print 1 2").unwrap_err();
+    let indexes = errors.iter().map(|x| x.token.index).collect::<Vec<_>>();
+    assert_eq!(indexes, [0, 7, 16, 29]);
+}
+
+#[test]
+fn line_breaks_after_operators_continue_statements() {
+    let ast = statements("+\n1 2\nprint\n\n(len\n\"ab\")");
+    assert!(matches!(ast[..], [Expr::Binary(..), Expr::Call(..)]));
+}
+
+#[test]
+fn incomplete_input() {
+    let incomplete = |src| lexer::is_incomplete(&parser::parse(src).unwrap());
+    for src in [
+        "(",
+        "+ 1 (* 2",
+        "{\nprint 1\n",
+        "{ (1) ",
+        "+",
+        "1; len\n",
+        "+ 1 {\n*\n",
+    ] {
+        assert!(incomplete(src), "{src:?}");
+    }
+    for src in ["", "\n", "1", "+ 1", "(1))", ") (", "{ 1 }", "read", "(1 }"] {
+        assert!(!incomplete(src), "{src:?}");
+    }
+}
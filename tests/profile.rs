@@ -0,0 +1,82 @@
+use vm::vm::{assemble, ExecutionError, VM};
+
+// count, percentage and name of every row per section, leaving out the times
+// and the order of rows with the same count, which both depend on timing
+fn sections(report: &str) -> Vec<Vec<(u64, String, String)>> {
+    report
+        .split_terminator("\n\n")
+        .map(|section| {
+            let mut rows = section
+                .lines()
+                .skip(1)
+                .map(|row| {
+                    let fields = row.split_whitespace().collect::<Vec<_>>();
+                    let count = fields[0].parse().unwrap();
+                    (count, fields[1].to_string(), fields[4..].join(" "))
+                })
+                .collect::<Vec<_>>();
+            assert!(rows.is_sorted_by(|a, b| a.0 >= b.0), "{section}");
+            rows.sort();
+            rows
+        })
+        .collect()
+}
+
+fn rows(rows: &[(u64, &str, &str)]) -> Vec<(u64, String, String)> {
+    let mut rows = rows
+        .iter()
+        .map(|(count, percent, name)| (*count, percent.to_string(), name.to_string()))
+        .collect::<Vec<_>>();
+    rows.sort();
+    rows
+}
+
+#[test]
+fn counts_are_grouped_by_instruction_opcode_and_label() {
+    let mut vm = VM::default();
+    vm.load(assemble("mov cx 0\nloop:\nadd cx 1\ncmp cx 3\ngotoeq loop -1\nlen cx").unwrap());
+    vm.enable_profiling();
+    assert_eq!(vm.exec(), Err(ExecutionError::TypeError));
+    let profile = vm.profile().unwrap();
+    // the failing `len` counts too
+    assert_eq!(profile.total(), 12);
+    assert_eq!(
+        sections(&profile.report(vm.code())),
+        [
+            rows(&[
+                (1, "8.33%", "0 mov cx 0"),
+                (1, "8.33%", "1 loop:"),
+                (3, "25.00%", "2 add cx 1"),
+                (3, "25.00%", "3 cmp cx 3"),
+                (3, "25.00%", "4 gotoeq loop -1"),
+                (1, "8.33%", "5 len cx"),
+            ]),
+            rows(&[
+                (1, "8.33%", "mov"),
+                (1, "8.33%", "mark"),
+                (3, "25.00%", "add"),
+                (3, "25.00%", "cmp"),
+                (3, "25.00%", "gotoeq"),
+                (1, "8.33%", "len"),
+            ]),
+            rows(&[(1, "8.33%", "<start>"), (11, "91.67%", "loop")]),
+        ]
+    );
+}
+
+#[test]
+fn instructions_that_never_ran_are_left_out() {
+    let mut vm = VM::default();
+    vm.load(assemble("goto end\nprint 1\nend:\nmov ax 1").unwrap());
+    vm.enable_profiling();
+    vm.exec().unwrap();
+    let profile = vm.profile().unwrap();
+    assert_eq!(
+        sections(&profile.report(vm.code())),
+        [
+            rows(&[(1, "50.00%", "0 goto end"), (1, "50.00%", "3 mov ax 1")]),
+            rows(&[(1, "50.00%", "goto"), (1, "50.00%", "mov")]),
+            rows(&[(1, "50.00%", "<start>"), (1, "50.00%", "end")]),
+        ]
+    );
+}
@@ -0,0 +1,107 @@
+use common::Buffer;
+use vm::{
+    snapshot,
+    vm::{assemble, ConstPool, ExecutionError, VM},
+};
+
+mod common;
+
+#[test]
+fn fuel_survives_a_snapshot() {
+    // more than a 32-bit `usize` holds
+    let fuel = Some(u32::MAX as u64 * 3);
+    let mut vm = VM::default();
+    vm.load(assemble("mov ax 1\nadd ax 2").unwrap());
+    vm.set_fuel(fuel);
+    vm.step();
+    let mut restored = VM::default();
+    restored.restore(&vm.snapshot()).unwrap();
+    assert_eq!(restored.fuel(), vm.fuel());
+    assert_eq!(restored.opptr(), 1);
+}
+
+#[test]
+fn missing_constants_survive_a_snapshot() {
+    let mut consts = ConstPool::default();
+    consts.intern("ab");
+    let mut vm = VM::default();
+    // `load_linked` points a constant missing from its pool at `usize::MAX`
+    vm.load_linked(assemble("print $0\nprint $1").unwrap(), &consts);
+    vm.load(assemble("print $5").unwrap());
+    let mut restored = VM::default();
+    restored.restore(&vm.snapshot()).unwrap();
+    let code = restored.code().iter().map(ToString::to_string);
+    assert_eq!(
+        code.collect::<Vec<_>>(),
+        [
+            "print $0".into(),
+            format!("print ${}", usize::MAX),
+            "print $5".into()
+        ]
+    );
+    let output = Buffer::default();
+    restored.set_output(Box::new(output.clone()));
+    assert_eq!(restored.exec(), Err(ExecutionError::NoSuchConstant));
+    assert_eq!(output.take(), "ab\n");
+}
+
+// the state `restore` replaces, to check a failed one leaves it alone
+fn state(vm: &VM) -> String {
+    let code = vm.code().iter().map(ToString::to_string);
+    format!(
+        "{:?} {:?} {:?} {} {:?}",
+        code.collect::<Vec<_>>(),
+        vm.stack(),
+        vm.regs(),
+        vm.opptr(),
+        vm.fuel()
+    )
+}
+
+#[test]
+fn bad_snapshots_are_rejected() {
+    let mut vm = VM::default();
+    vm.load(assemble("push 1\nmov ax 2\nprint ax").unwrap());
+    vm.step();
+    let data = vm.snapshot();
+    let payload = &data[22..];
+
+    let mut version = data.clone();
+    version[4..6].copy_from_slice(&u16::MAX.to_le_bytes());
+    let mut magic = data.clone();
+    magic[0] = b'X';
+    let mut corrupted = data.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    let mut trailing = data.clone();
+    trailing.push(0);
+    let mut trailing_payload = payload.to_vec();
+    trailing_payload.push(0);
+
+    let cases = [
+        (version, "UnsupportedVersion(65535)"),
+        (magic, "BadMagic"),
+        (data[..10].to_vec(), "BadMagic"),
+        (corrupted, "ChecksumMismatch"),
+        (data[..data.len() - 1].to_vec(), "ChecksumMismatch"),
+        (trailing, "ChecksumMismatch"),
+        // sealed again, so that the header is fine and the payload is not
+        (
+            snapshot::seal(payload[..payload.len() - 1].to_vec()),
+            "Decode(UnexpectedEnd)",
+        ),
+        (snapshot::seal(trailing_payload), "Decode(TrailingData)"),
+    ];
+
+    let mut restored = VM::default();
+    restored.load(assemble("mov bx 3").unwrap());
+    restored.set_fuel(Some(7));
+    restored.step();
+    let before = state(&restored);
+    for (data, expected) in cases {
+        let err = restored.restore(&data).unwrap_err();
+        assert_eq!(format!("{err:?}"), expected);
+        assert_eq!(state(&restored), before, "{expected}");
+    }
+    restored.restore(&vm.snapshot()).unwrap();
+    assert_eq!(state(&restored), state(&vm));
+}
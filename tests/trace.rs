@@ -0,0 +1,24 @@
+use common::Buffer;
+use vm::{
+    trace::TextTracer,
+    vm::{assemble, VM},
+};
+
+mod common;
+
+#[test]
+fn each_instruction_is_logged_with_registers_and_stack_top() {
+    let trace = Buffer::default();
+    let mut vm = VM::default();
+    vm.set_tracer(Some(Box::new(TextTracer::new(Box::new(trace.clone())))));
+    vm.load(assemble("mov ax 2\npush ax").unwrap());
+    vm.exec().unwrap();
+    // registers and stack as each instruction left them, `-` for no stack
+    assert_eq!(
+        trace.take(),
+        concat!(
+            "   0  mov ax 2                 ax=2 bx=0 cx=0 cmp=0 top=-\n",
+            "   1  push ax                  ax=2 bx=0 cx=0 cmp=0 top=2\n",
+        )
+    );
+}
@@ -0,0 +1,65 @@
+use vm::{verify::verify, vm::assemble};
+
+fn diagnostics(src: &str) -> Vec<String> {
+    verify(&assemble(src).unwrap())
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[test]
+fn one_diagnostic_per_kind() {
+    let cases: &[(&str, &str)] = &[
+        ("goto end", "error at 0: no mark `end`"),
+        ("a:\na:", "error at 1: mark `a` is defined twice"),
+        (
+            "push 1\npop ax\npop bx",
+            "error at 2: pop may run on an empty stack",
+        ),
+        (
+            "a:\npush 1\ngoto a",
+            "error at 1: reached with stack depth 0 and 1",
+        ),
+        ("len cmp", "error at 0: illegal use of register cmp"),
+        ("goto end\nprint 1\nend:", "warning at 1: unreachable code"),
+    ];
+    for (src, expected) in cases {
+        assert_eq!(diagnostics(src), [*expected], "{src:?}");
+    }
+}
+
+#[test]
+fn clean_programs_have_no_diagnostics() {
+    for src in [
+        "",
+        "mov ax 1\nprint ax",
+        // both ways out of `gotoeq` reach `a` with one value on the stack
+        "push 1\ncmp 1 1\ngotoeq a 0\npush 2\npop ax\na:\npop bx",
+        "mov cx 0\nloop:\nadd cx 1\ncmp cx 5\ngotoeq loop -1",
+    ] {
+        assert_eq!(diagnostics(src), [] as [String; 0], "{src:?}");
+    }
+}
+
+#[test]
+fn code_after_an_underflow_is_still_reachable() {
+    assert_eq!(
+        diagnostics("pop ax\npush 1\nprint ax"),
+        ["error at 0: pop may run on an empty stack"]
+    );
+}
+
+#[test]
+fn unreachable_runs_are_reported_once() {
+    assert_eq!(
+        diagnostics("goto end\nprint 1\nskip:\nprint 2\nend:\nprint 3"),
+        ["warning at 1: unreachable code"]
+    );
+}
+
+#[test]
+fn computed_jumps_turn_off_the_unreachable_check() {
+    for src in ["mov opi 3\nprint 1\nprint 2", "jmp 2\nprint 1\nprint 2"] {
+        assert_eq!(diagnostics(src), [] as [String; 0], "{src:?}");
+    }
+}